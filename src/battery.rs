@@ -1,66 +1,311 @@
+use crate::bc12::Budget;
+use crate::bq24250::{self, ChargeState, Fault, InputLimit};
 use crate::hal::{
     gpio::{
         gpiob::{PB8, PB9},
-        Analog, OpenDrain, Output,
+        Analog,
     },
-    i2c::I2c,
     prelude::*,
     rcc::Rcc,
 };
-use crate::leds::ChargeLed;
+use crate::health::BatteryHealth;
+use crate::i2c_bus::I2cBus;
+use crate::jeita::{ChargeProfile, Limits};
+use crate::leds::{BlinkCode, ChargeLed, Layer, Pattern, BREATHE_PERIOD_MS};
 use crate::pac::I2C1;
+use crate::stc3115::{self, Reading, Stc3115};
+use core::fmt::{self, Write};
 
+/// Battery state shared with the interrupt handlers. Only the hand-over
+/// to and from `BatteryMonitor` is locked, so nothing here waits on I2C.
 pub struct BatteryState {
-    i2c: I2c<I2C1, PB9<Output<OpenDrain>>, PB8<Output<OpenDrain>>>,
     charge_led: ChargeLed,
-    buffer: [u8; 2],
+    /// Gauge reading from the last update
+    reading: Option<Reading>,
+    /// An update has come in since the last start or ship mode
+    reported: bool,
+    gauge_starting: bool,
+    input_budget: Budget,
+    ship_mode: bool,
+    /// Ship mode was asked for, and waits on the Zynq to shut down
+    ship_pending: bool,
+    status_requested: bool,
     should_update: bool,
+    /// An update was taken by idle and its report isn't in yet
+    updating: bool,
     last_update: u32,
 }
 
-const BQ24250_ADDR: u8 = 0x6A;
-const STC3115_ADDR: u8 = 0x70;
+/// The charger and fuel gauge, owned by idle so the I2C transfers, with
+/// their timeouts, retries and bus recovery, never run under a lock
+pub struct BatteryMonitor {
+    i2c: I2cBus,
+    gauge: Stc3115,
+    health: BatteryHealth,
+    charger_configured: bool,
+    profile: ChargeProfile,
+    /// Charge limits last written to the charger
+    applied_limits: Option<Limits>,
+    input_budget: Budget,
+    applied_budget: Option<Budget>,
+    charger: Option<bq24250::Status>,
+    usb_detect: Option<bq24250::UsbDetect>,
+    ts_status: Option<bq24250::TsStatus>,
+    charger_fault: Fault,
+    fault_log: FaultLog,
+    recoveries: u8,
+    /// Updates with signs of a missing cell since the last good reading,
+    /// not necessarily in a row
+    absent_signs: u8,
+    /// The charger and gauge were put in ship mode
+    shipped: bool,
+}
+
+/// What an update has to work with, taken from `BatteryState`
+#[derive(Clone, Copy)]
+pub struct Job {
+    tick: u32,
+    input_budget: Budget,
+    ship_mode: bool,
+    write_status: bool,
+}
+
+impl Job {
+    /// Whether the host asked for the status with this update
+    pub fn write_status(&self) -> bool {
+        self.write_status
+    }
+}
+
+/// What an update found, handed back to `BatteryState`
+pub struct Report {
+    reading: Option<Reading>,
+    gauge_starting: bool,
+    /// Charge LED base pattern, left alone when the charger didn't answer
+    pattern: Option<Pattern>,
+    code: Option<BlinkCode>,
+}
+
 const UPDATE_INTERVAL: u32 = 5;
+/// Recovery steps allowed until the charger finishes a charge
+const MAX_CHARGER_RECOVERIES: u8 = 2;
+const FAULT_LOG_LEN: usize = 4;
+/// Updates that have to point at a missing cell, with no good reading in
+/// between, before we go with it. They needn't be in a row, the charger
+/// flips between states without one.
+const ABSENT_AFTER: u8 = 3;
+/// Charge LED while running from USB with no cell fitted, a short blip
+/// every two seconds
+const NO_BATTERY_PATTERN: Pattern = Pattern::Blink {
+    period_ticks: 20,
+    on_ticks: 1,
+};
+/// Charge LED for a battery check without a gauge reading, a flicker no
+/// fault code or charge state uses
+const NO_READING_PATTERN: Pattern = Pattern::Blink {
+    period_ticks: 2,
+    on_ticks: 1,
+};
+/// Dimmest the charge LED goes for an empty battery on a battery check
+const EMPTY_LEVEL: u16 = 100;
+
+/// Charge settings for our 2000 mAh cell
+const CHARGER_CONFIG: bq24250::Config = bq24250::Config {
+    // until we know what the port can do
+    input_limit: InputLimit::Usb100mA,
+    charge_current_ma: 1000,
+    regulation_mv: 4200,
+    termination_ma: Some(100),
+    vin_dpm_mv: 4440,
+    safety_timer: bq24250::SafetyTimer::Hours6,
+    timer_extension: true,
+    ts_enabled: true,
+    // USB only, anything past 6.5 V is a broken supply
+    input_ovp: bq24250::InputOvp::V6_5,
+    low_charge: false,
+    watchdog: true,
+};
+
+#[derive(Clone, Copy)]
+struct FaultRecord {
+    at: u32,
+    fault: Fault,
+}
+
+/// The last few charger faults, with the tick they showed up on
+struct FaultLog {
+    records: [Option<FaultRecord>; FAULT_LOG_LEN],
+    next: usize,
+}
+
+impl FaultLog {
+    const fn new() -> Self {
+        Self {
+            records: [None; FAULT_LOG_LEN],
+            next: 0,
+        }
+    }
+
+    fn record(&mut self, at: u32, fault: Fault) {
+        self.records[self.next] = Some(FaultRecord { at, fault });
+        self.next = (self.next + 1) % FAULT_LOG_LEN;
+    }
+
+    /// Records from oldest to newest
+    fn records(&self) -> impl Iterator<Item = &FaultRecord> {
+        (0..FAULT_LOG_LEN)
+            .filter_map(move |i| self.records[(self.next + i) % FAULT_LOG_LEN].as_ref())
+    }
+}
 
 impl BatteryState {
-    pub fn new(
-        i2c1: I2C1,
-        scl: PB8<Analog>,
-        sda: PB9<Analog>,
-        charge_led: ChargeLed,
-        rcc: &mut Rcc,
-    ) -> Self {
-        let i2c = i2c1.i2c(
-            sda.into_open_drain_output(),
-            scl.into_open_drain_output(),
-            400.khz(),
-            rcc,
-        );
+    pub fn new(charge_led: ChargeLed) -> Self {
         Self {
-            i2c,
             charge_led,
-            buffer: [0; 2],
-            last_update: 0,
+            reading: None,
+            reported: false,
+            gauge_starting: false,
+            input_budget: Budget::Limit(CHARGER_CONFIG.input_limit),
+            ship_mode: false,
+            ship_pending: false,
+            status_requested: false,
             should_update: true,
+            updating: false,
+            last_update: 0,
         }
     }
 
-    pub fn update_if_needed(&mut self) {
+    /// Hands idle the next update, if one is due
+    pub fn take_job(&mut self) -> Option<Job> {
         if !self.should_update {
-            return;
+            return None;
         }
         self.should_update = false;
-        self.i2c
-            .write_read(BQ24250_ADDR, &[0x0], &mut self.buffer[0..1])
-            .unwrap();
-        match (self.buffer[0] & 0x30) >> 4 {
-            0 => self.charge_led.off(),
-            1 => self.charge_led.blink(),
-            2 => self.charge_led.on(),
-            _ => self.charge_led.off(),
+        self.updating = true;
+        let write_status = self.status_requested;
+        self.status_requested = false;
+        Some(Job {
+            tick: self.last_update,
+            input_budget: self.input_budget,
+            ship_mode: self.ship_mode,
+            write_status,
+        })
+    }
+
+    /// Takes in what idle found for `job`
+    pub fn publish(&mut self, job: &Job, report: Report) {
+        self.updating = false;
+        self.reading = report.reading;
+        self.gauge_starting = report.gauge_starting;
+        self.reported = !job.ship_mode && !self.ship_mode;
+        if job.ship_mode != self.ship_mode {
+            // ship mode came or went meanwhile, the LED is already right
+            return;
+        }
+        if let Some(pattern) = report.pattern {
+            self.charge_led.set(Layer::Base, pattern, job.tick);
+        }
+        match report.code {
+            Some(code) => self.charge_led.set(Layer::Fault, code.pattern(), job.tick),
+            None => self.charge_led.clear(Layer::Fault),
+        }
+    }
+
+    /// Whether a reading may still be on its way, so no reading doesn't
+    /// tell us anything about the cell yet. That's until the first update
+    /// after a start or ship mode, and while the gauge hasn't converted.
+    pub fn reading_pending(&self) -> bool {
+        !self.reported || self.gauge_starting
+    }
+
+    /// Ship mode for storage, as far as this board goes. The cell stays
+    /// connected: SYSOFF would cut the system rail the SMC runs from, and
+    /// neither the switch nor VBUS detect is on a WKUP pin for Standby.
+    /// So the SMC uses the normal Stop mode, with the charger's watchdog
+    /// off, the gauge in standby and nothing read until `exit_ship_mode`.
+    /// The charger's input only goes high impedance when a good reading
+    /// shows there's a cell to run from.
+    pub fn enter_ship_mode(&mut self, tick: u32) {
+        self.ship_pending = false;
+        self.ship_mode = true;
+        self.reported = false;
+        // idle does the I2C side before it sleeps
+        self.should_update = true;
+        self.charge_led.set(Layer::Base, Pattern::Off, tick);
+        self.charge_led.clear(Layer::Fault);
+    }
+
+    /// Asks for ship mode once the Zynq is off, see `ship_mode_pending`
+    pub fn request_ship_mode(&mut self) {
+        self.ship_pending = true;
+    }
+
+    /// Whether ship mode should go on as soon as the Zynq is off
+    pub fn ship_mode_pending(&self) -> bool {
+        self.ship_pending
+    }
+
+    /// Leaves ship mode, or drops a request for it
+    pub fn exit_ship_mode(&mut self) {
+        self.ship_pending = false;
+        self.ship_mode = false;
+        self.reported = false;
+        self.should_update = true;
+    }
+
+    pub fn in_ship_mode(&self) -> bool {
+        self.ship_mode
+    }
+
+    /// Reads the charger and gauge again the next time idle runs, rather
+    /// than wait out the update interval
+    pub fn request_update(&mut self) {
+        self.should_update = true;
+    }
+
+    /// Whether an update is due or still running
+    pub fn update_pending(&self) -> bool {
+        self.should_update || self.updating
+    }
+
+    /// Has idle write the status for the host after a fresh update
+    pub fn request_status(&mut self) {
+        self.status_requested = true;
+        self.should_update = true;
+    }
+
+    /// Sets what the charger may draw from USB, applied on the next update
+    pub fn set_input_budget(&mut self, budget: Budget) {
+        if budget != self.input_budget {
+            self.input_budget = budget;
+            self.should_update = true;
         }
     }
 
+    /// Shows the state of charge on the charge LED for `ticks`, as a
+    /// steady brightness from dim when empty to full when full, which no
+    /// fault code looks like
+    pub fn show_charge_level(&mut self, tick: u32, ticks: u32) {
+        let pattern = match self.reading {
+            Some(reading) => Pattern::Dim {
+                level: EMPTY_LEVEL
+                    + (reading.soc.min(1000) as u32 * (1000 - EMPTY_LEVEL) as u32 / 1000) as u16,
+            },
+            None => NO_READING_PATTERN,
+        };
+        self.charge_led.flash(Layer::Notice, pattern, tick, ticks);
+    }
+
+    /// Whether the charge LED is showing a battery check
+    pub fn is_showing_level(&self) -> bool {
+        self.charge_led.is_flashing()
+    }
+
+    /// Latest fuel gauge measurements, if the gauge has answered yet
+    pub fn reading(&self) -> Option<&Reading> {
+        self.reading.as_ref()
+    }
+
     pub fn tick(&mut self, tick: u32) {
         self.charge_led.tick(tick);
         if tick >= self.last_update + UPDATE_INTERVAL {
@@ -69,3 +314,306 @@ impl BatteryState {
         }
     }
 }
+
+impl BatteryMonitor {
+    pub fn new(i2c1: I2C1, scl: PB8<Analog>, sda: PB9<Analog>, rcc: &mut Rcc) -> Self {
+        let i2c = i2c1.i2c(
+            sda.into_open_drain_output(),
+            scl.into_open_drain_output(),
+            400.khz(),
+            rcc,
+        );
+        Self {
+            i2c: I2cBus::new(i2c, [bq24250::ADDR, stc3115::ADDR]),
+            gauge: Stc3115::new(),
+            health: BatteryHealth::load(),
+            charger_configured: false,
+            profile: ChargeProfile::new(),
+            applied_limits: None,
+            input_budget: Budget::Limit(CHARGER_CONFIG.input_limit),
+            applied_budget: None,
+            charger: None,
+            usb_detect: None,
+            ts_status: None,
+            charger_fault: Fault::None,
+            fault_log: FaultLog::new(),
+            recoveries: 0,
+            absent_signs: 0,
+            shipped: false,
+        }
+    }
+
+    /// Keeps the charger programmed and reads it and the gauge, or puts
+    /// both in ship mode, for what `job` asks
+    pub fn update(&mut self, job: &Job) -> Report {
+        self.input_budget = job.input_budget;
+        if job.ship_mode {
+            if !self.shipped {
+                self.ship();
+            }
+            return self.report(Some(Pattern::Off), None);
+        }
+        if self.shipped {
+            self.shipped = false;
+            // programming it from scratch also takes it out of HZ mode
+            self.charger_configured = false;
+        }
+        let tick = job.tick;
+        if self.charger_configured {
+            // keeps the charger from dropping back to its defaults
+            self.charger_configured = bq24250::reset_watchdog(&mut self.i2c).is_ok();
+        } else {
+            self.applied_limits = None;
+            self.applied_budget = None;
+            self.charger_configured = bq24250::configure(&mut self.i2c, &CHARGER_CONFIG).is_ok();
+        }
+        self.apply_limits();
+        self.apply_budget();
+        let charger = bq24250::status(&mut self.i2c);
+        let mut pattern = None;
+        let charger_code = match charger {
+            Ok(status) => {
+                pattern = Some(match status.state {
+                    ChargeState::Charging => Pattern::Breathe {
+                        period_ms: BREATHE_PERIOD_MS,
+                    },
+                    ChargeState::Done => Pattern::Solid,
+                    ChargeState::Ready | ChargeState::Fault => Pattern::Off,
+                });
+                self.handle_charger_status(status, tick)
+            }
+            Err(_) => Some(BlinkCode::Charger),
+        };
+        self.charger = charger.ok();
+        self.usb_detect = bq24250::usb_detect(&mut self.i2c).ok();
+        self.ts_status = bq24250::ts_status(&mut self.i2c).ok();
+        let gauge_ok = self.gauge.update(&mut self.i2c).is_ok();
+        if let Some(reading) = self.gauge.reading() {
+            let charge = self.charger.map(|status| status.state);
+            self.health.update(reading, charge, tick);
+            // takes effect on the next update
+            self.profile.update(reading.temperature_c);
+        }
+        self.update_absent();
+        if self.is_absent() {
+            // whatever the charger makes of it, the state is known
+            return self.report(Some(NO_BATTERY_PATTERN), None);
+        }
+
+        // plugged in but held off by the temperature
+        let suspended = !self.limits().charge_enabled
+            && self
+                .charger
+                .map_or(false, |status| status.state == ChargeState::Ready);
+        let code = match (charger_code, gauge_ok) {
+            (Some(code), _) => Some(code),
+            (None, false) => Some(BlinkCode::Gauge),
+            (None, true) if suspended => Some(BlinkCode::ChargerTemperature),
+            (None, true) => None,
+        };
+        self.report(pattern, code)
+    }
+
+    fn report(&self, pattern: Option<Pattern>, code: Option<BlinkCode>) -> Report {
+        Report {
+            reading: self.gauge.reading().copied(),
+            gauge_starting: self.gauge.is_starting(),
+            pattern,
+            code,
+        }
+    }
+
+    /// The I2C side of `BatteryState::enter_ship_mode`
+    fn ship(&mut self) {
+        self.shipped = true;
+        bq24250::set_watchdog(&mut self.i2c, false).ok();
+        if self.gauge.reading().is_some() {
+            bq24250::set_hz_mode(&mut self.i2c, true).ok();
+        }
+        self.gauge.standby(&mut self.i2c).ok();
+    }
+
+    /// Whether the board is running from USB with no cell fitted
+    fn is_absent(&self) -> bool {
+        self.absent_signs >= ABSENT_AFTER
+    }
+
+    /// A good gauge reading means there's a cell, whatever the charger
+    /// says. Without one, the charger reporting no battery or the gauge
+    /// seeing the battery fail both count against it.
+    fn update_absent(&mut self) {
+        let charger_sees_none = self
+            .charger
+            .map_or(false, |status| status.fault == Fault::NoBattery);
+        if self.gauge.reading().is_some() {
+            self.absent_signs = 0;
+        } else if charger_sees_none || self.gauge.battery_failed() {
+            self.absent_signs = self.absent_signs.saturating_add(1);
+        }
+    }
+
+    /// Charge limits for the cell's temperature band
+    fn limits(&self) -> Limits {
+        self.profile.band().limits(
+            CHARGER_CONFIG.charge_current_ma,
+            CHARGER_CONFIG.regulation_mv,
+        )
+    }
+
+    /// Writes the limits for the temperature band to the charger, if it
+    /// doesn't have them already
+    fn apply_limits(&mut self) {
+        let limits = self.limits();
+        if !self.charger_configured || self.applied_limits == Some(limits) {
+            return;
+        }
+        let written = bq24250::set_charge_current(&mut self.i2c, limits.charge_current_ma)
+            .and_then(|()| bq24250::set_regulation_voltage(&mut self.i2c, limits.regulation_mv))
+            .and_then(|()| bq24250::set_charge_enabled(&mut self.i2c, limits.charge_enabled));
+        if written.is_ok() {
+            self.applied_limits = Some(limits);
+        }
+    }
+
+    /// Writes the USB input budget to the charger, if it doesn't have it
+    /// already. A suspended bus gets high impedance mode, as nothing the
+    /// charger can limit to is low enough.
+    fn apply_budget(&mut self) {
+        let budget = self.input_budget;
+        if !self.charger_configured || self.applied_budget == Some(budget) {
+            return;
+        }
+        if budget == Budget::Suspended && self.gauge.reading().is_none() {
+            // only a good reading proves there's a cell to run from, without
+            // one cutting the input would cut our own power
+            return;
+        }
+        let written = match budget {
+            Budget::Limit(limit) => bq24250::set_input_limit(&mut self.i2c, limit)
+                .and_then(|()| bq24250::set_hz_mode(&mut self.i2c, false)),
+            Budget::Suspended => bq24250::set_hz_mode(&mut self.i2c, true),
+        };
+        if written.is_ok() {
+            self.applied_budget = Some(budget);
+        }
+    }
+
+    /// Tracks charger faults, tries to recover where that's safe, and
+    /// returns the code to show for the current one
+    fn handle_charger_status(&mut self, status: bq24250::Status, tick: u32) -> Option<BlinkCode> {
+        if status.watchdog_fault {
+            // the charger went back to its defaults, program it again
+            self.charger_configured = false;
+        }
+        let fault = match (status.state, status.fault) {
+            // no input supply isn't a fault, just running from the battery
+            (ChargeState::Fault, Fault::InputUndervoltage) | (ChargeState::Fault, Fault::Sleep) => {
+                Fault::None
+            }
+            (ChargeState::Fault, fault) => fault,
+            _ => Fault::None,
+        };
+        if status.state == ChargeState::Done {
+            self.recoveries = 0;
+        }
+        if fault != self.charger_fault {
+            self.charger_fault = fault;
+            if fault != Fault::None {
+                self.fault_log.record(tick, fault);
+                self.recover(fault);
+            }
+        }
+        match fault {
+            Fault::None | Fault::InputUndervoltage | Fault::Sleep => None,
+            Fault::InputOvervoltage | Fault::InputFaultLdoLow => Some(BlinkCode::ChargerInput),
+            Fault::BatteryTemperature | Fault::ThermalShutdown => {
+                Some(BlinkCode::ChargerTemperature)
+            }
+            Fault::BatteryOvervoltage => Some(BlinkCode::BatteryOvervoltage),
+            Fault::SafetyTimer => Some(BlinkCode::SafetyTimer),
+            Fault::NoBattery => Some(BlinkCode::NoBattery),
+            Fault::IsetShort | Fault::Unknown(_) => Some(BlinkCode::Charger),
+        }
+    }
+
+    /// One recovery step for a newly seen fault. Input, temperature and
+    /// overvoltage faults clear by themselves once the cause goes away.
+    fn recover(&mut self, fault: Fault) {
+        if self.recoveries >= MAX_CHARGER_RECOVERIES {
+            return;
+        }
+        match fault {
+            Fault::SafetyTimer => {
+                // toggling charge enable restarts the timer, but only
+                // a bounded number of times before the cell is done
+                self.recoveries += 1;
+                bq24250::set_charge_enabled(&mut self.i2c, false).ok();
+                if self.limits().charge_enabled {
+                    bq24250::set_charge_enabled(&mut self.i2c, true).ok();
+                }
+            }
+            Fault::IsetShort | Fault::Unknown(_) => {
+                self.recoveries += 1;
+                self.charger_configured = false;
+            }
+            Fault::NoBattery => {
+                // a cell fitted since won't be seen until the next check
+                self.recoveries += 1;
+                bq24250::force_battery_detect(&mut self.i2c).ok();
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes the latest charger and gauge readings for the host
+    pub fn write_status<W: Write>(&self, w: &mut W) -> fmt::Result {
+        match self.charger {
+            Some(status) => writeln!(
+                w,
+                "@smc charger {:?} fault={:?}{} usb={:?} input={:?} band={:?} ts={:?}",
+                status.state,
+                status.fault,
+                if status.watchdog_fault {
+                    " watchdog-fault"
+                } else {
+                    ""
+                },
+                self.usb_detect,
+                self.input_budget,
+                self.profile.band(),
+                self.ts_status,
+            )?,
+            None => writeln!(w, "@smc charger no-charger")?,
+        }
+        self.i2c.write_health(w)?;
+        for record in self.fault_log.records() {
+            writeln!(
+                w,
+                "@smc charger-fault at={}.{}s {:?}",
+                record.at / 10,
+                record.at % 10,
+                record.fault
+            )?;
+        }
+        match self.gauge.reading() {
+            Some(reading) => writeln!(
+                w,
+                "@smc battery soc={}.{}% {}mV {}mA {}C{}{}",
+                reading.soc / 10,
+                reading.soc % 10,
+                reading.voltage_mv,
+                reading.current_ma,
+                reading.temperature_c,
+                if reading.soc_alarm { " soc-alarm" } else { "" },
+                if reading.voltage_alarm {
+                    " voltage-alarm"
+                } else {
+                    ""
+                },
+            ),
+            None if self.is_absent() => writeln!(w, "@smc battery absent")?,
+            None => writeln!(w, "@smc battery no-gauge")?,
+        }
+        self.health.write_status(w)
+    }
+}
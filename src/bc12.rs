@@ -0,0 +1,171 @@
+//! USB battery charging (BC1.2) port detection with the BCDR block, run
+//! on VBUS attach to find out how much current the port can supply.
+
+use crate::bq24250::InputLimit;
+use crate::pac::USB;
+use usb_device::device::UsbDeviceState;
+
+const BCDR_BCDEN: u32 = 1 << 0;
+const BCDR_DCDEN: u32 = 1 << 1;
+const BCDR_PDEN: u32 = 1 << 2;
+const BCDR_SDEN: u32 = 1 << 3;
+const BCDR_DCDET: u32 = 1 << 4;
+const BCDR_PDET: u32 = 1 << 5;
+const BCDR_SDET: u32 = 1 << 6;
+/// D+ pull-up, the host only sees us while it's on
+const BCDR_DPPU: u32 = 1 << 15;
+
+/// Ticks to wait for the data pins to make contact before going on
+/// without it, BC1.2 allows up to 900 ms
+const CONTACT_TIMEOUT_TICKS: u32 = 6;
+
+/// What's on the other end of the cable
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Port {
+    /// Standard downstream port, 100 mA until configured, then 500 mA
+    Standard,
+    /// Charging downstream port, 1.5 A and data
+    ChargingDownstream,
+    /// Dedicated charger, 1.5 A and no data
+    DedicatedCharger,
+}
+
+/// What the charger may draw from the port
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Budget {
+    Limit(InputLimit),
+    /// The host suspended the bus, run from the battery
+    Suspended,
+}
+
+impl Budget {
+    /// Current the charger may draw, 0 where it isn't known
+    pub fn milliamps(self) -> u32 {
+        match self {
+            Budget::Limit(InputLimit::Usb100mA) => 100,
+            Budget::Limit(InputLimit::Usb150mA) => 150,
+            Budget::Limit(InputLimit::Usb500mA) => 500,
+            Budget::Limit(InputLimit::Usb900mA) => 900,
+            Budget::Limit(InputLimit::Charger1500mA) => 1500,
+            Budget::Limit(InputLimit::Charger2000mA) => 2000,
+            Budget::Limit(InputLimit::External) | Budget::Suspended => 0,
+            Budget::Limit(InputLimit::Unlimited) => u32::MAX,
+        }
+    }
+}
+
+impl Port {
+    pub fn budget(self, state: UsbDeviceState) -> Budget {
+        match (self, state) {
+            // a charging port keeps supplying its current while suspended,
+            // and a dedicated charger has no SOFs so it always looks it
+            (Port::DedicatedCharger, _) | (Port::ChargingDownstream, _) => {
+                Budget::Limit(InputLimit::Charger1500mA)
+            }
+            (Port::Standard, UsbDeviceState::Suspend) => Budget::Suspended,
+            (Port::Standard, UsbDeviceState::Configured) => Budget::Limit(InputLimit::Usb500mA),
+            (Port::Standard, _) => Budget::Limit(InputLimit::Usb100mA),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Step {
+    Idle,
+    /// Waits a tick so the pull-up isn't fought over while re-enumerating
+    Pending,
+    Contact {
+        ticks: u32,
+    },
+    Primary,
+    Secondary,
+    Done(Port),
+}
+
+/// Runs detection a step per tick, which gives each one the 40 ms or so
+/// the lines need to settle
+pub struct ChargerDetect {
+    step: Step,
+}
+
+impl ChargerDetect {
+    pub const fn new() -> Self {
+        Self { step: Step::Idle }
+    }
+
+    /// Starts detection over, for a newly attached cable
+    pub fn start(&mut self) {
+        self.step = Step::Pending;
+    }
+
+    /// Gives up on detection, for a detached cable
+    pub fn stop(&mut self) {
+        match self.step {
+            Step::Contact { .. } | Step::Primary | Step::Secondary => set_bcdr(BCDR_DPPU),
+            _ => {}
+        }
+        self.step = Step::Idle;
+    }
+
+    /// Whether detection is still working out the port
+    pub fn is_running(&self) -> bool {
+        match self.step {
+            Step::Idle | Step::Done(_) => false,
+            _ => true,
+        }
+    }
+
+    /// The port found, once detection is done
+    pub fn port(&self) -> Option<Port> {
+        match self.step {
+            Step::Done(port) => Some(port),
+            _ => None,
+        }
+    }
+
+    pub fn tick(&mut self) {
+        let bcdr = unsafe { &*USB::ptr() }.bcdr.read().bits();
+        self.step = match self.step {
+            Step::Pending => {
+                // the pull-up goes off while the lines are probed
+                set_bcdr(BCDR_BCDEN | BCDR_DCDEN);
+                Step::Contact { ticks: 0 }
+            }
+            Step::Contact { ticks } => {
+                if bcdr & BCDR_DCDET != 0 || ticks >= CONTACT_TIMEOUT_TICKS {
+                    set_bcdr(BCDR_BCDEN | BCDR_PDEN);
+                    Step::Primary
+                } else {
+                    Step::Contact { ticks: ticks + 1 }
+                }
+            }
+            Step::Primary => {
+                if bcdr & BCDR_PDET != 0 {
+                    set_bcdr(BCDR_BCDEN | BCDR_SDEN);
+                    Step::Secondary
+                } else {
+                    finish(Port::Standard)
+                }
+            }
+            Step::Secondary => {
+                if bcdr & BCDR_SDET != 0 {
+                    finish(Port::DedicatedCharger)
+                } else {
+                    finish(Port::ChargingDownstream)
+                }
+            }
+            step => step,
+        };
+    }
+}
+
+/// Turns detection off and the pull-up back on, so a host can enumerate us
+fn finish(port: Port) -> Step {
+    set_bcdr(BCDR_DPPU);
+    Step::Done(port)
+}
+
+fn set_bcdr(bits: u32) {
+    let usb = unsafe { &*USB::ptr() };
+    usb.bcdr.write(|w| unsafe { w.bits(bits) });
+}
@@ -0,0 +1,443 @@
+//! BQ24250 switch mode charger, register access and decoded fields.
+//!
+//! The setting enums cover every value a field can take, whether or not
+//! the firmware uses it, hence their `allow(dead_code)`.
+
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+pub const ADDR: u8 = 0x6A;
+
+#[derive(Clone, Copy)]
+enum Register {
+    /// Watchdog, charge state and fault
+    Status = 0,
+    /// Reset, input current limit, termination, charge enable, HZ mode
+    Control = 1,
+    /// Battery regulation voltage and USB detection result
+    Voltage = 2,
+    /// Charge and termination current
+    Current = 3,
+    /// Low charge, D+/D- detection and input DPM voltage
+    Vindpm = 4,
+    /// Safety timer, SYSOFF and TS/NTC monitoring
+    Safety = 5,
+    /// Input overvoltage threshold and battery detection. FORCE_PTM, the
+    /// production test mode, is left alone.
+    Ovp = 6,
+}
+
+const STATUS_WD_FAULT: u8 = 1 << 7;
+const STATUS_WD_EN: u8 = 1 << 6;
+const STATUS_STAT: u8 = 0b11 << 4;
+const STATUS_FAULT: u8 = 0b1111;
+/// Writing 1 resets every register, reads back 0
+const CONTROL_RESET: u8 = 1 << 7;
+const CONTROL_IIN_LIMIT: u8 = 0b111 << 4;
+const CONTROL_TE: u8 = 1 << 2;
+/// Active low, charging is enabled while it's clear
+const CONTROL_CE_N: u8 = 1 << 1;
+const CONTROL_HZ_MODE: u8 = 1 << 0;
+const VOLTAGE_VBATREG: u8 = 0b11_1111 << 2;
+const VOLTAGE_USB_DET: u8 = 0b11;
+const CURRENT_ICHG: u8 = 0b1_1111 << 3;
+const CURRENT_ITERM: u8 = 0b111;
+const VINDPM_LOW_CHG: u8 = 1 << 5;
+/// Writing 1 starts the charger's own D+/D- detection
+const VINDPM_DPDM_EN: u8 = 1 << 4;
+const VINDPM_VINDPM: u8 = 0b111;
+/// Doubles the safety timer while charging is slowed by DPM or heat
+const SAFETY_2XTMR_EN: u8 = 1 << 7;
+const SAFETY_TMR: u8 = 0b11 << 5;
+const SAFETY_TS_EN: u8 = 1 << 3;
+const SAFETY_TS_STAT: u8 = 0b111;
+const OVP_VOVP: u8 = 0b111 << 5;
+/// Writing 1 starts a battery detection cycle, clears itself
+const OVP_FORCE_BATDET: u8 = 1 << 3;
+/// Bits that start something when written as 1, never written back by a
+/// read-modify-write
+const ACTION_BITS: [(Register, u8); 3] = [
+    (Register::Control, CONTROL_RESET),
+    (Register::Vindpm, VINDPM_DPDM_EN),
+    (Register::Ovp, OVP_FORCE_BATDET),
+];
+
+/// Charge state from the STAT bits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChargeState {
+    Ready,
+    Charging,
+    Done,
+    Fault,
+}
+
+/// Fault from the FAULT bits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    None,
+    InputOvervoltage,
+    InputUndervoltage,
+    /// Input below the battery voltage
+    Sleep,
+    /// TS/NTC says the battery is too hot or too cold
+    BatteryTemperature,
+    BatteryOvervoltage,
+    ThermalShutdown,
+    SafetyTimer,
+    NoBattery,
+    IsetShort,
+    InputFaultLdoLow,
+    Unknown(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Status {
+    pub state: ChargeState,
+    pub fault: Fault,
+    /// The I2C watchdog expired and the registers went back to defaults
+    pub watchdog_fault: bool,
+}
+
+/// Input current limit, the USB ones match what the port may supply
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(dead_code)]
+pub enum InputLimit {
+    Usb100mA,
+    Usb150mA,
+    Usb500mA,
+    Usb900mA,
+    Charger1500mA,
+    Charger2000mA,
+    /// Set by the resistor on ILIM
+    External,
+    Unlimited,
+}
+
+/// Port type found by the charger's own D+/D- detection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsbDetect {
+    DedicatedCharger,
+    ChargingDownstreamPort,
+    StandardDownstreamPort,
+    /// Apple or other proprietary charger, or D+/D- left open
+    NonStandard,
+}
+
+/// Battery temperature zone from the TS_STAT bits, in JEITA terms
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TsStatus {
+    Normal,
+    /// Above the hot threshold, charging suspended
+    Hot,
+    /// Between warm and hot, charging at reduced voltage
+    Warm,
+    /// Between cold and cool, charging at reduced current
+    Cool,
+    /// Below the cold threshold, charging suspended
+    Cold,
+    /// Below freezing, charging suspended
+    Freezing,
+    /// No thermistor, or TS monitoring is off
+    Open,
+    Unknown(u8),
+}
+
+/// Input overvoltage protection threshold
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(dead_code)]
+pub enum InputOvp {
+    V6_0,
+    V6_5,
+    V7_0,
+    V8_0,
+    V9_0,
+    V9_5,
+    V10_0,
+    V10_5,
+}
+
+/// Charge safety timer
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(dead_code)]
+pub enum SafetyTimer {
+    Minutes45,
+    Hours6,
+    Hours9,
+    Disabled,
+}
+
+/// Everything the firmware sets on the charger rather than leave at reset
+/// defaults
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub input_limit: InputLimit,
+    /// 500 to 2000 mA in 50 mA steps
+    pub charge_current_ma: u16,
+    /// 3500 to 4440 mV in 20 mV steps
+    pub regulation_mv: u16,
+    /// 50 to 225 mA in 25 mA steps, `None` to not terminate
+    pub termination_ma: Option<u16>,
+    /// 4200 to 4760 mV in 80 mV steps
+    pub vin_dpm_mv: u16,
+    pub safety_timer: SafetyTimer,
+    /// Double the safety timer while charging is slowed down
+    pub timer_extension: bool,
+    pub ts_enabled: bool,
+    pub input_ovp: InputOvp,
+    /// Charge at the fixed low current instead of the programmed one
+    pub low_charge: bool,
+    /// The 50 s I2C watchdog, which resets the registers if not serviced
+    pub watchdog: bool,
+}
+
+pub fn status<I, E>(i2c: &mut I) -> Result<Status, E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let value = read(i2c, Register::Status)?;
+    let state = match (value & STATUS_STAT) >> 4 {
+        0 => ChargeState::Ready,
+        1 => ChargeState::Charging,
+        2 => ChargeState::Done,
+        _ => ChargeState::Fault,
+    };
+    let fault = match value & STATUS_FAULT {
+        0 => Fault::None,
+        1 => Fault::InputOvervoltage,
+        2 => Fault::InputUndervoltage,
+        3 => Fault::Sleep,
+        4 => Fault::BatteryTemperature,
+        5 => Fault::BatteryOvervoltage,
+        6 => Fault::ThermalShutdown,
+        7 => Fault::SafetyTimer,
+        8 => Fault::NoBattery,
+        9 => Fault::IsetShort,
+        10 => Fault::InputFaultLdoLow,
+        other => Fault::Unknown(other),
+    };
+    Ok(Status {
+        state,
+        fault,
+        watchdog_fault: value & STATUS_WD_FAULT != 0,
+    })
+}
+
+/// Writes the whole configuration, register by register
+pub fn configure<I, E>(i2c: &mut I, config: &Config) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    set_input_limit(i2c, config.input_limit)?;
+    set_charge_current(i2c, config.charge_current_ma)?;
+    set_regulation_voltage(i2c, config.regulation_mv)?;
+    set_termination(i2c, config.termination_ma)?;
+    set_vin_dpm(i2c, config.vin_dpm_mv)?;
+    set_safety_timer(i2c, config.safety_timer)?;
+    set_timer_extension(i2c, config.timer_extension)?;
+    set_ts_enabled(i2c, config.ts_enabled)?;
+    set_input_ovp(i2c, config.input_ovp)?;
+    set_low_charge(i2c, config.low_charge)?;
+    set_watchdog(i2c, config.watchdog)?;
+    set_hz_mode(i2c, false)?;
+    set_charge_enabled(i2c, true)
+}
+
+pub fn set_input_limit<I, E>(i2c: &mut I, limit: InputLimit) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = match limit {
+        InputLimit::Usb100mA => 0,
+        InputLimit::Usb150mA => 1,
+        InputLimit::Usb500mA => 2,
+        InputLimit::Usb900mA => 3,
+        InputLimit::Charger1500mA => 4,
+        InputLimit::Charger2000mA => 5,
+        InputLimit::External => 6,
+        InputLimit::Unlimited => 7,
+    };
+    modify(i2c, Register::Control, CONTROL_IIN_LIMIT, bits << 4)
+}
+
+pub fn set_charge_current<I, E>(i2c: &mut I, ma: u16) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = (ma.max(500).min(2000) - 500) / 50;
+    modify(i2c, Register::Current, CURRENT_ICHG, (bits as u8) << 3)
+}
+
+pub fn set_regulation_voltage<I, E>(i2c: &mut I, mv: u16) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = (mv.max(3500).min(4440) - 3500) / 20;
+    modify(i2c, Register::Voltage, VOLTAGE_VBATREG, (bits as u8) << 2)
+}
+
+pub fn set_termination<I, E>(i2c: &mut I, ma: Option<u16>) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    if let Some(ma) = ma {
+        let bits = (ma.max(50).min(225) - 50) / 25;
+        modify(i2c, Register::Current, CURRENT_ITERM, bits as u8)?;
+    }
+    let te = if ma.is_some() { CONTROL_TE } else { 0 };
+    modify(i2c, Register::Control, CONTROL_TE, te)
+}
+
+pub fn set_vin_dpm<I, E>(i2c: &mut I, mv: u16) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = (mv.max(4200).min(4760) - 4200) / 80;
+    modify(i2c, Register::Vindpm, VINDPM_VINDPM, bits as u8)
+}
+
+pub fn set_safety_timer<I, E>(i2c: &mut I, timer: SafetyTimer) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = match timer {
+        SafetyTimer::Minutes45 => 0,
+        SafetyTimer::Hours6 => 1,
+        SafetyTimer::Hours9 => 2,
+        SafetyTimer::Disabled => 3,
+    };
+    modify(i2c, Register::Safety, SAFETY_TMR, bits << 5)
+}
+
+pub fn set_timer_extension<I, E>(i2c: &mut I, enabled: bool) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = if enabled { SAFETY_2XTMR_EN } else { 0 };
+    modify(i2c, Register::Safety, SAFETY_2XTMR_EN, bits)
+}
+
+pub fn set_ts_enabled<I, E>(i2c: &mut I, enabled: bool) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = if enabled { SAFETY_TS_EN } else { 0 };
+    modify(i2c, Register::Safety, SAFETY_TS_EN, bits)
+}
+
+pub fn ts_status<I, E>(i2c: &mut I) -> Result<TsStatus, E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    Ok(match read(i2c, Register::Safety)? & SAFETY_TS_STAT {
+        0 => TsStatus::Normal,
+        1 => TsStatus::Hot,
+        2 => TsStatus::Warm,
+        3 => TsStatus::Cool,
+        4 => TsStatus::Cold,
+        5 => TsStatus::Freezing,
+        6 => TsStatus::Open,
+        other => TsStatus::Unknown(other),
+    })
+}
+
+pub fn set_input_ovp<I, E>(i2c: &mut I, ovp: InputOvp) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = match ovp {
+        InputOvp::V6_0 => 0,
+        InputOvp::V6_5 => 1,
+        InputOvp::V7_0 => 2,
+        InputOvp::V8_0 => 3,
+        InputOvp::V9_0 => 4,
+        InputOvp::V9_5 => 5,
+        InputOvp::V10_0 => 6,
+        InputOvp::V10_5 => 7,
+    };
+    modify(i2c, Register::Ovp, OVP_VOVP, bits << 5)
+}
+
+/// Makes the charger check for a battery again, rather than wait for it
+/// to notice by itself
+pub fn force_battery_detect<I, E>(i2c: &mut I) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    modify(i2c, Register::Ovp, OVP_FORCE_BATDET, OVP_FORCE_BATDET)
+}
+
+pub fn set_low_charge<I, E>(i2c: &mut I, enabled: bool) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = if enabled { VINDPM_LOW_CHG } else { 0 };
+    modify(i2c, Register::Vindpm, VINDPM_LOW_CHG, bits)
+}
+
+pub fn set_watchdog<I, E>(i2c: &mut I, enabled: bool) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = if enabled { STATUS_WD_EN } else { 0 };
+    modify(i2c, Register::Status, STATUS_WD_EN, bits)
+}
+
+/// Restarts the 50 s I2C watchdog, writing WD_EN does that
+pub fn reset_watchdog<I, E>(i2c: &mut I) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    modify(i2c, Register::Status, STATUS_WD_EN, STATUS_WD_EN)
+}
+
+pub fn set_charge_enabled<I, E>(i2c: &mut I, enabled: bool) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = if enabled { 0 } else { CONTROL_CE_N };
+    modify(i2c, Register::Control, CONTROL_CE_N, bits)
+}
+
+/// High impedance mode disconnects the input, the battery runs the system
+pub fn set_hz_mode<I, E>(i2c: &mut I, enabled: bool) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let bits = if enabled { CONTROL_HZ_MODE } else { 0 };
+    modify(i2c, Register::Control, CONTROL_HZ_MODE, bits)
+}
+
+pub fn usb_detect<I, E>(i2c: &mut I) -> Result<UsbDetect, E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    Ok(match read(i2c, Register::Voltage)? & VOLTAGE_USB_DET {
+        0 => UsbDetect::DedicatedCharger,
+        1 => UsbDetect::ChargingDownstreamPort,
+        2 => UsbDetect::StandardDownstreamPort,
+        _ => UsbDetect::NonStandard,
+    })
+}
+
+fn read<I, E>(i2c: &mut I, register: Register) -> Result<u8, E>
+where
+    I: WriteRead<Error = E>,
+{
+    let mut value = [0; 1];
+    i2c.write_read(ADDR, &[register as u8], &mut value)?;
+    Ok(value[0])
+}
+
+/// Read-modify-write of the bits in `mask`. Action bits outside `mask`
+/// are written as 0, so they don't fire again.
+fn modify<I, E>(i2c: &mut I, register: Register, mask: u8, bits: u8) -> Result<(), E>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    let actions = ACTION_BITS
+        .iter()
+        .filter(|&&(action_register, _)| action_register as u8 == register as u8)
+        .fold(0, |actions, &(_, bit)| actions | bit);
+    let value = read(i2c, register)? & !(actions & !mask);
+    let value = (value & !mask) | (bits & mask);
+    i2c.write(ADDR, &[register as u8, value])
+}
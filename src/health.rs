@@ -0,0 +1,173 @@
+//! Long term battery bookkeeping: cycle count, learned capacity, state of
+//! health, and time to empty or full from the recent current.
+
+use crate::bq24250::ChargeState;
+use crate::settings::{self, Slot};
+use crate::stc3115::{Reading, DESIGN_CAPACITY_MAH};
+use core::fmt::{self, Write};
+
+/// Tenths of a percent discharged per full cycle
+const CYCLE_SOC: u32 = 1000;
+/// Discharge is persisted in steps of this many tenths of a percent, to
+/// spare the EEPROM
+const PERSIST_SOC: u32 = 100;
+/// A charge has to cover at least this much, in tenths of a percent, to
+/// teach us the capacity
+const MIN_LEARN_SOC: u16 = 500;
+/// Gaps between readings longer than this, in ticks, mean the SMC slept
+/// and the current in between is unknown
+const MAX_GAP_TICKS: u32 = 50;
+/// mA ticks per mAh, at 10 ticks a second
+const MA_TICKS_PER_MAH: i32 = 36_000;
+/// Weight of a new reading in the current average, as a shift
+const AVERAGE_SHIFT: u32 = 3;
+
+/// A charge being measured for capacity learning
+#[derive(Clone, Copy)]
+struct Learning {
+    start_soc: u16,
+    /// Charge put in so far, in mA ticks
+    charged: i32,
+}
+
+pub struct BatteryHealth {
+    /// Total discharge over the cell's life, in tenths of a percent
+    discharged: u32,
+    /// Full charge capacity learned from complete charges
+    capacity_mah: u32,
+    last_soc: Option<u16>,
+    last_tick: Option<u32>,
+    average_ma: i32,
+    learning: Option<Learning>,
+}
+
+impl BatteryHealth {
+    pub fn load() -> Self {
+        Self {
+            discharged: settings::read(Slot::BatteryDischarged),
+            capacity_mah: settings::read(Slot::BatteryCapacity),
+            last_soc: None,
+            last_tick: None,
+            average_ma: 0,
+            learning: None,
+        }
+    }
+
+    /// Complete charge and discharge cycles, in tenths
+    pub fn cycles_tenths(&self) -> u32 {
+        self.discharged / (CYCLE_SOC / 10)
+    }
+
+    /// Learned capacity as a percentage of the design capacity
+    pub fn health_percent(&self) -> u32 {
+        self.capacity_mah * 100 / DESIGN_CAPACITY_MAH
+    }
+
+    /// Minutes until empty at the recent average current, while discharging
+    pub fn time_to_empty_min(&self) -> Option<u32> {
+        let soc = self.last_soc? as u32;
+        if self.average_ma >= 0 {
+            return None;
+        }
+        let remaining_mah = self.capacity_mah * soc / 1000;
+        Some(remaining_mah * 60 / -self.average_ma as u32)
+    }
+
+    /// Minutes until full at the recent average current, while charging
+    pub fn time_to_full_min(&self) -> Option<u32> {
+        let soc = self.last_soc? as u32;
+        if self.average_ma <= 0 {
+            return None;
+        }
+        let missing_mah = self.capacity_mah * 1000u32.saturating_sub(soc) / 1000;
+        Some(missing_mah * 60 / self.average_ma as u32)
+    }
+
+    /// Takes in a new gauge reading, and the charge state if the charger
+    /// answered
+    pub fn update(&mut self, reading: &Reading, charge: Option<ChargeState>, tick: u32) {
+        let elapsed = self
+            .last_tick
+            .map(|last| tick.wrapping_sub(last))
+            .filter(|&elapsed| elapsed <= MAX_GAP_TICKS);
+        self.last_tick = Some(tick);
+        let current = reading.current_ma as i32;
+        self.average_ma += (current - self.average_ma) >> AVERAGE_SHIFT;
+
+        if let Some(last_soc) = self.last_soc {
+            if reading.soc < last_soc {
+                self.add_discharge((last_soc - reading.soc) as u32);
+            }
+        }
+        self.last_soc = Some(reading.soc);
+
+        match (charge, self.learning.as_mut()) {
+            (Some(ChargeState::Charging), Some(learning)) => match elapsed {
+                Some(elapsed) => learning.charged += current * elapsed as i32,
+                // the count has a hole in it, so it's no good any more
+                None => self.learning = None,
+            },
+            (Some(ChargeState::Charging), None) => {
+                self.learning = Some(Learning {
+                    start_soc: reading.soc,
+                    charged: 0,
+                })
+            }
+            (Some(ChargeState::Done), Some(learning)) => {
+                let learning = *learning;
+                self.learning = None;
+                self.learn(learning);
+            }
+            // charging stopped short, or we can't tell
+            (_, Some(_)) => self.learning = None,
+            (_, None) => {}
+        }
+    }
+
+    /// Writes the health figures for the host
+    pub fn write_status<W: Write>(&self, w: &mut W) -> fmt::Result {
+        let cycles = self.cycles_tenths();
+        write!(
+            w,
+            "@smc battery-health cycles={}.{} capacity={}mAh health={}% current-avg={}mA",
+            cycles / 10,
+            cycles % 10,
+            self.capacity_mah,
+            self.health_percent(),
+            self.average_ma,
+        )?;
+        if let Some(minutes) = self.time_to_empty_min() {
+            write!(w, " to-empty={}min", minutes)?;
+        }
+        if let Some(minutes) = self.time_to_full_min() {
+            write!(w, " to-full={}min", minutes)?;
+        }
+        writeln!(w)
+    }
+
+    fn add_discharge(&mut self, soc: u32) {
+        let before = self.discharged / PERSIST_SOC;
+        self.discharged = self.discharged.saturating_add(soc);
+        if self.discharged / PERSIST_SOC != before {
+            settings::write(Slot::BatteryDischarged, self.discharged);
+        }
+    }
+
+    /// Capacity from a finished charge, scaled up from the part of a full
+    /// charge it covered and folded into what we knew
+    fn learn(&mut self, learning: Learning) {
+        let covered = 1000u16.saturating_sub(learning.start_soc);
+        if covered < MIN_LEARN_SOC || learning.charged <= 0 {
+            return;
+        }
+        let charged_mah = (learning.charged / MA_TICKS_PER_MAH) as u32;
+        let measured = charged_mah * 1000 / covered as u32;
+        // one charge can be off, so it only moves the estimate a quarter
+        // of the way, within what a real cell can do
+        let capacity = (self.capacity_mah * 3 + measured) / 4;
+        self.capacity_mah = capacity
+            .max(DESIGN_CAPACITY_MAH / 2)
+            .min(DESIGN_CAPACITY_MAH * 11 / 10);
+        settings::write(Slot::BatteryCapacity, self.capacity_mah);
+    }
+}
@@ -0,0 +1,313 @@
+//! Shared I2C1 bus for the charger and the fuel gauge.
+//!
+//! Transfers run on the I2C registers directly so every wait is bounded.
+//! Failed transfers are retried, and a bus that looks stuck gets the usual
+//! recovery: SCL clocked by hand until the slave lets go of SDA, then a
+//! STOP. Each device keeps error counts, and one that keeps failing is
+//! marked degraded and only gets a single try per transfer, so it can't
+//! hold up the rest of power management.
+
+use crate::hal::{
+    gpio::{
+        gpiob::{PB8, PB9},
+        OpenDrain, Output,
+    },
+    i2c::I2c,
+};
+use crate::pac::{GPIOB, I2C1};
+use core::fmt;
+use cortex_m::asm::delay;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+const ISR_TXIS: u32 = 1 << 1;
+const ISR_RXNE: u32 = 1 << 2;
+const ISR_NACKF: u32 = 1 << 4;
+const ISR_STOPF: u32 = 1 << 5;
+const ISR_TC: u32 = 1 << 6;
+const ISR_BERR: u32 = 1 << 8;
+const ISR_ARLO: u32 = 1 << 9;
+const ICR_CLEAR_ALL: u32 = (1 << 3) | (1 << 4) | (1 << 5) | (1 << 8) | (1 << 9) | (1 << 10);
+const CR1_PE: u32 = 1 << 0;
+const CR2_RD_WRN: u32 = 1 << 10;
+const CR2_START: u32 = 1 << 13;
+const CR2_STOP: u32 = 1 << 14;
+const CR2_AUTOEND: u32 = 1 << 25;
+
+const SCL_PIN: u32 = 8;
+const SDA_PIN: u32 = 9;
+/// GPIO MODER values
+const MODE_OUTPUT: u32 = 0b01;
+const MODE_ALTERNATE: u32 = 0b10;
+
+/// Polls of a status flag before a transfer gives up, a few ms at 24 MHz
+const TIMEOUT_POLLS: u32 = 10_000;
+/// Half an SCL period during recovery, about 5 us at 24 MHz
+const RECOVERY_HALF_CYCLES: u32 = 120;
+/// Extra tries after a failed transfer
+const MAX_RETRIES: u8 = 2;
+/// Failed transfers in a row before a device counts as degraded
+const DEGRADED_AFTER: u8 = 3;
+const DEVICE_COUNT: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The device didn't acknowledge, it may be busy or missing
+    Nack,
+    ArbitrationLost,
+    BusError,
+    /// A flag never came, usually SCL or SDA held low
+    Timeout,
+}
+
+#[derive(Clone, Copy)]
+struct Device {
+    addr: u8,
+    errors: u32,
+    failed_in_row: u8,
+    degraded: bool,
+}
+
+pub struct I2cBus {
+    /// Kept so nothing else claims the peripheral or the pins, the
+    /// transfers below use the registers
+    _i2c: I2c<I2C1, PB9<Output<OpenDrain>>, PB8<Output<OpenDrain>>>,
+    devices: [Device; DEVICE_COUNT],
+    recoveries: u32,
+}
+
+impl I2cBus {
+    /// Takes the configured peripheral and the addresses of the devices
+    /// to keep health counters for
+    pub fn new(
+        i2c: I2c<I2C1, PB9<Output<OpenDrain>>, PB8<Output<OpenDrain>>>,
+        addrs: [u8; DEVICE_COUNT],
+    ) -> Self {
+        let device = |addr| Device {
+            addr,
+            errors: 0,
+            failed_in_row: 0,
+            degraded: false,
+        };
+        Self {
+            _i2c: i2c,
+            devices: [device(addrs[0]), device(addrs[1])],
+            recoveries: 0,
+        }
+    }
+
+    /// Whether the device at `addr` has been failing
+    pub fn is_degraded(&self, addr: u8) -> bool {
+        self.devices
+            .iter()
+            .any(|device| device.addr == addr && device.degraded)
+    }
+
+    /// Writes the error counters for the host
+    pub fn write_health<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        for device in self.devices.iter() {
+            writeln!(
+                w,
+                "@smc i2c 0x{:02x} errors={}{}",
+                device.addr,
+                device.errors,
+                if device.degraded { " degraded" } else { "" }
+            )?;
+        }
+        writeln!(w, "@smc i2c recoveries={}", self.recoveries)
+    }
+
+    /// Runs `transfer`, retrying and recovering the bus as needed, and
+    /// keeps the device's counters
+    fn with_retries<F>(&mut self, addr: u8, mut transfer: F) -> Result<(), Error>
+    where
+        F: FnMut() -> Result<(), Error>,
+    {
+        let retries = if self.is_degraded(addr) {
+            0
+        } else {
+            MAX_RETRIES
+        };
+        let mut result = transfer();
+        for _ in 0..retries {
+            match result {
+                Ok(()) => break,
+                // the device is busy or gone, the bus itself is fine
+                Err(Error::Nack) => {}
+                Err(_) => self.recover(),
+            }
+            result = transfer();
+        }
+
+        if let Some(device) = self.devices.iter_mut().find(|device| device.addr == addr) {
+            match result {
+                Ok(()) => {
+                    device.failed_in_row = 0;
+                    device.degraded = false;
+                }
+                Err(_) => {
+                    device.errors = device.errors.saturating_add(1);
+                    device.failed_in_row = device.failed_in_row.saturating_add(1);
+                    device.degraded = device.failed_in_row >= DEGRADED_AFTER;
+                }
+            }
+        }
+        result
+    }
+
+    /// Frees a stuck bus. With the peripheral off, SCL is clocked by hand
+    /// until the slave releases SDA, at most 9 times, then a STOP is sent
+    /// before the pins go back to I2C.
+    fn recover(&mut self) {
+        let i2c = unsafe { &*I2C1::ptr() };
+        let gpiob = unsafe { &*GPIOB::ptr() };
+        self.recoveries = self.recoveries.saturating_add(1);
+
+        i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !CR1_PE) });
+        set_pin(SDA_PIN, true);
+        set_pin(SCL_PIN, true);
+        set_mode(SCL_PIN, MODE_OUTPUT);
+        set_mode(SDA_PIN, MODE_OUTPUT);
+
+        for _ in 0..9 {
+            if gpiob.idr.read().bits() & (1 << SDA_PIN) != 0 {
+                break;
+            }
+            set_pin(SCL_PIN, false);
+            delay(RECOVERY_HALF_CYCLES);
+            set_pin(SCL_PIN, true);
+            delay(RECOVERY_HALF_CYCLES);
+        }
+
+        // STOP, SDA rising while SCL is high
+        set_pin(SCL_PIN, false);
+        delay(RECOVERY_HALF_CYCLES);
+        set_pin(SDA_PIN, false);
+        delay(RECOVERY_HALF_CYCLES);
+        set_pin(SCL_PIN, true);
+        delay(RECOVERY_HALF_CYCLES);
+        set_pin(SDA_PIN, true);
+        delay(RECOVERY_HALF_CYCLES);
+
+        set_mode(SCL_PIN, MODE_ALTERNATE);
+        set_mode(SDA_PIN, MODE_ALTERNATE);
+        // toggling PE resets the peripheral's state machine
+        i2c.icr.write(|w| unsafe { w.bits(ICR_CLEAR_ALL) });
+        i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() | CR1_PE) });
+    }
+}
+
+impl Write for I2cBus {
+    type Error = Error;
+
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
+        self.with_retries(addr, || write(addr, bytes))
+    }
+}
+
+impl WriteRead for I2cBus {
+    type Error = Error;
+
+    fn write_read(&mut self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        self.with_retries(addr, || write_read(addr, bytes, buffer))
+    }
+}
+
+fn write(addr: u8, bytes: &[u8]) -> Result<(), Error> {
+    start(addr, bytes.len(), false, true);
+    let result = send(bytes).and_then(|()| wait_for(ISR_STOPF));
+    finish(result)
+}
+
+fn write_read(addr: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+    start(addr, bytes.len(), false, false);
+    let result = send(bytes)
+        .and_then(|()| wait_for(ISR_TC))
+        .and_then(|()| {
+            // repeated start for the read
+            start(addr, buffer.len(), true, true);
+            receive(buffer)
+        })
+        .and_then(|()| wait_for(ISR_STOPF));
+    finish(result)
+}
+
+fn start(addr: u8, len: usize, read: bool, autoend: bool) {
+    let i2c = unsafe { &*I2C1::ptr() };
+    let mut cr2 = ((addr as u32) << 1) | ((len as u32 & 0xff) << 16) | CR2_START;
+    if read {
+        cr2 |= CR2_RD_WRN;
+    }
+    if autoend {
+        cr2 |= CR2_AUTOEND;
+    }
+    i2c.cr2.write(|w| unsafe { w.bits(cr2) });
+}
+
+fn send(bytes: &[u8]) -> Result<(), Error> {
+    let i2c = unsafe { &*I2C1::ptr() };
+    for &byte in bytes {
+        wait_for(ISR_TXIS)?;
+        i2c.txdr.write(|w| unsafe { w.bits(byte as u32) });
+    }
+    Ok(())
+}
+
+fn receive(buffer: &mut [u8]) -> Result<(), Error> {
+    let i2c = unsafe { &*I2C1::ptr() };
+    for byte in buffer.iter_mut() {
+        wait_for(ISR_RXNE)?;
+        *byte = i2c.rxdr.read().bits() as u8;
+    }
+    Ok(())
+}
+
+/// Waits for `flag`, bailing out on any error flag or after the timeout
+fn wait_for(flag: u32) -> Result<(), Error> {
+    let i2c = unsafe { &*I2C1::ptr() };
+    for _ in 0..TIMEOUT_POLLS {
+        let isr = i2c.isr.read().bits();
+        if isr & ISR_ARLO != 0 {
+            return Err(Error::ArbitrationLost);
+        }
+        if isr & ISR_BERR != 0 {
+            return Err(Error::BusError);
+        }
+        if isr & ISR_NACKF != 0 {
+            return Err(Error::Nack);
+        }
+        if isr & flag != 0 {
+            return Ok(());
+        }
+    }
+    Err(Error::Timeout)
+}
+
+/// Makes sure the bus ends up stopped and the flags clear, whatever
+/// happened to the transfer
+fn finish(result: Result<(), Error>) -> Result<(), Error> {
+    let i2c = unsafe { &*I2C1::ptr() };
+    if result.is_err() {
+        i2c.cr2
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR2_STOP) });
+        for _ in 0..TIMEOUT_POLLS {
+            if i2c.isr.read().bits() & ISR_STOPF != 0 {
+                break;
+            }
+        }
+    }
+    i2c.icr.write(|w| unsafe { w.bits(ICR_CLEAR_ALL) });
+    result
+}
+
+fn set_pin(pin: u32, high: bool) {
+    let gpiob = unsafe { &*GPIOB::ptr() };
+    let bit = if high { 1 << pin } else { 1 << (pin + 16) };
+    gpiob.bsrr.write(|w| unsafe { w.bits(bit) });
+}
+
+fn set_mode(pin: u32, mode: u32) {
+    let gpiob = unsafe { &*GPIOB::ptr() };
+    gpiob
+        .moder
+        .modify(|r, w| unsafe { w.bits((r.bits() & !(0b11 << (pin * 2))) | (mode << (pin * 2))) });
+}
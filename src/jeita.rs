@@ -0,0 +1,137 @@
+//! JEITA style charge profile, cutting back the charge as the cell gets
+//! cold or hot and stopping it at the extremes.
+//!
+//! The temperature comes from the fuel gauge, which sits against the cell.
+//! The charger's own TS input still cuts charging in hardware if the gauge
+//! stops answering.
+
+/// Band edges in °C, a band starts at its edge going up
+const COOL_C: i8 = 0;
+const NORMAL_C: i8 = 10;
+const WARM_C: i8 = 45;
+const HOT_C: i8 = 60;
+/// How far back past an edge the temperature has to go to change band
+const HYSTERESIS_C: i8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Band {
+    /// Below 0 °C, no charging
+    Cold,
+    /// 0 to 10 °C, reduced current
+    Cool,
+    Normal,
+    /// 45 to 60 °C, reduced current and voltage
+    Warm,
+    /// Above 60 °C, no charging
+    Hot,
+}
+
+/// What the charger should be set to for a band
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub charge_enabled: bool,
+    pub charge_current_ma: u16,
+    pub regulation_mv: u16,
+}
+
+impl Band {
+    fn index(self) -> u8 {
+        match self {
+            Band::Cold => 0,
+            Band::Cool => 1,
+            Band::Normal => 2,
+            Band::Warm => 3,
+            Band::Hot => 4,
+        }
+    }
+
+    fn warmer(self, other: Band) -> Band {
+        if self.index() >= other.index() {
+            self
+        } else {
+            other
+        }
+    }
+
+    fn cooler(self, other: Band) -> Band {
+        if self.index() <= other.index() {
+            self
+        } else {
+            other
+        }
+    }
+
+    /// Band for a temperature, ignoring where we came from
+    fn of(temperature_c: i8) -> Self {
+        if temperature_c < COOL_C {
+            Band::Cold
+        } else if temperature_c < NORMAL_C {
+            Band::Cool
+        } else if temperature_c < WARM_C {
+            Band::Normal
+        } else if temperature_c < HOT_C {
+            Band::Warm
+        } else {
+            Band::Hot
+        }
+    }
+
+    /// Limits within the band, from the ones for a cell at room temperature
+    pub fn limits(self, charge_current_ma: u16, regulation_mv: u16) -> Limits {
+        match self {
+            Band::Cold | Band::Hot => Limits {
+                charge_enabled: false,
+                charge_current_ma,
+                regulation_mv,
+            },
+            Band::Cool => Limits {
+                charge_enabled: true,
+                charge_current_ma: charge_current_ma / 2,
+                regulation_mv,
+            },
+            Band::Normal => Limits {
+                charge_enabled: true,
+                charge_current_ma,
+                regulation_mv,
+            },
+            Band::Warm => Limits {
+                charge_enabled: true,
+                charge_current_ma: charge_current_ma / 2,
+                regulation_mv: regulation_mv - 100,
+            },
+        }
+    }
+}
+
+pub struct ChargeProfile {
+    band: Band,
+}
+
+impl ChargeProfile {
+    pub const fn new() -> Self {
+        Self { band: Band::Normal }
+    }
+
+    pub fn band(&self) -> Band {
+        self.band
+    }
+
+    /// Moves to the band for a new temperature. Heading back toward
+    /// normal takes clearing the edge by the hysteresis, so a cell sitting
+    /// on an edge doesn't toggle charging.
+    pub fn update(&mut self, temperature_c: i8) -> Band {
+        let band = Band::of(temperature_c);
+        let recovering = |margin: i8| Band::of(temperature_c.saturating_add(margin));
+        let normal = Band::Normal.index();
+        self.band = if band.index() > self.band.index() && self.band.index() < normal {
+            // warming up out of the cold bands
+            recovering(-HYSTERESIS_C).warmer(self.band)
+        } else if band.index() < self.band.index() && self.band.index() > normal {
+            // cooling down out of the hot bands
+            recovering(HYSTERESIS_C).cooler(self.band)
+        } else {
+            band
+        };
+        self.band
+    }
+}
@@ -1,4 +1,5 @@
 use crate::pac::TIM2;
+use cortex_m::asm::delay;
 use stm32l0xx_hal::{
     gpio::{
         gpiob::{PB10, PB11},
@@ -9,95 +10,484 @@ use stm32l0xx_hal::{
     rcc::Rcc,
 };
 
-pub struct StatusLed {
-    pwm: pwm::Pwm<TIM2, pwm::C4, pwm::Assigned<PB11<Analog>>>,
-}
+pub type StatusLed = Led<pwm::C4, pwm::Assigned<PB11<Analog>>>;
+pub type ChargeLed = Led<pwm::C3, pwm::Assigned<PB10<Analog>>>;
 
-pub struct ChargeLed {
-    pwm: pwm::Pwm<TIM2, pwm::C3, pwm::Assigned<PB10<Analog>>>,
-    blinking: bool,
-    blinking_up: bool,
-    blink_index: usize,
+/// A PWM driven LED showing the highest priority pattern anyone asked for
+pub struct Led<C, P> {
+    /// Kept so nothing else claims the channel, TIM2's interrupt sets
+    /// the compare register
+    _pwm: pwm::Pwm<TIM2, C, P>,
+    channel: usize,
+    layers: [Option<Active>; LAYER_COUNT],
+    shown: Option<Pattern>,
 }
 
+/// Channel indices into `CHANNELS`
+const STATUS_CHANNEL: usize = 0;
+const CHARGE_CHANNEL: usize = 1;
+
 pub fn create_leds(
     pb10: PB10<Analog>,
     pb11: PB11<Analog>,
     tim2: TIM2,
     rcc: &mut Rcc,
 ) -> (StatusLed, ChargeLed) {
-    let timer2 = pwm::Timer::new(tim2, 10.khz(), rcc);
+    // one update interrupt per ms steps the fades, and the slow PWM
+    // leaves plenty of resolution for the dim end of the gamma curve
+    let timer2 = pwm::Timer::new(tim2, 1.khz(), rcc);
     let mut status = timer2.channel4.assign(pb11);
     let mut charge = timer2.channel3.assign(pb10);
 
     status.enable();
     charge.enable();
 
+    let max_duty = status.get_max_duty() as u32;
+    unsafe {
+        CHANNELS[STATUS_CHANNEL].max_duty = max_duty * STATUS_MAX_DUTY / MAX_DUTY_DIVISOR;
+        CHANNELS[CHARGE_CHANNEL].max_duty = max_duty * CHARGE_MAX_DUTY / MAX_DUTY_DIVISOR;
+    }
+
     (
-        StatusLed { pwm: status },
-        ChargeLed {
-            pwm: charge,
-            blinking: false,
-            blinking_up: true,
-            blink_index: 0,
-        },
+        Led::new(status, STATUS_CHANNEL),
+        Led::new(charge, CHARGE_CHANNEL),
     )
 }
 
-const BLINK_MAX_DUTY: u16 = 600;
-const BLINK_MIN_DUTY: u16 = 20;
-const BLINK_DUTY_TABLE: [u16; 29] = [
-    0, 1, 1, 1, 2, 3, 4, 5, 6, 8, 10, 13, 16, 21, 26, 32, 41, 51, 64, 80, 99, 124, 154, 193, 240,
-    299, 373, 465, 579,
+/// Full brightness as a fraction of the PWM period
+const STATUS_MAX_DUTY: u32 = 2;
+const CHARGE_MAX_DUTY: u32 = 3;
+const MAX_DUTY_DIVISOR: u32 = 16;
+
+/// Brightness is in permille of an LED's max duty, before gamma correction
+const FULL: u16 = 1000;
+/// Breathing bottoms out here rather than going dark
+const BREATHE_MIN: u32 = 30;
+/// How long switching between steady levels takes
+const FADE_MS: u32 = 200;
+pub const BREATHE_PERIOD_MS: u32 = 4000;
+/// Gamma 2.2, brightness in 32 steps to a 16 bit fraction of max duty
+const GAMMA_TABLE: [u32; 33] = [
+    0, 32, 147, 359, 676, 1104, 1648, 2314, 3104, 4022, 5072, 6255, 7574, 9033, 10632, 12375,
+    14263, 16298, 18482, 20816, 23303, 25943, 28739, 31692, 34802, 38072, 41503, 45097, 48853,
+    52774, 56860, 61114, 65535,
 ];
-const STATUS_MAX_DUTY: u16 = 400;
+const HEARTBEAT_PERIOD_TICKS: u32 = 12;
+/// Each blink of a code is on then off for this many ticks
+const CODE_BLINK_TICKS: u32 = 2;
+/// Dark gap between repeats of a code
+const CODE_PAUSE_TICKS: u32 = 10;
+/// Core cycles in a tick at the 24 MHz sysclock, for blinking after a panic
+const PANIC_CYCLES_PER_TICK: u32 = 2_400_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+    Off,
+    Solid,
+    /// Steady at `level` permille of full brightness
+    Dim {
+        level: u16,
+    },
+    /// On for `on_ticks` out of every `period_ticks`
+    Blink {
+        period_ticks: u32,
+        on_ticks: u32,
+    },
+    /// Fade up and down, run by TIM2 so it's smooth whatever the tick does
+    Breathe {
+        period_ms: u32,
+    },
+    /// Fade from whatever is showing down to dark over `ms`
+    FadeOut {
+        ms: u32,
+    },
+    /// Two quick pulses, then a pause
+    Heartbeat,
+    /// `count` blinks, then a pause, repeated
+    Code {
+        count: u32,
+    },
+}
 
-impl StatusLed {
-    pub fn on(&mut self) {
-        self.pwm.set_duty(STATUS_MAX_DUTY);
+impl Pattern {
+    /// Brightness `ticks` into one of the tick driven patterns
+    fn brightness(self, ticks: u32) -> u16 {
+        if let Pattern::Dim { level } = self {
+            return level.min(FULL);
+        }
+        let on = match self {
+            Pattern::Off | Pattern::Breathe { .. } | Pattern::FadeOut { .. } => false,
+            Pattern::Solid | Pattern::Dim { .. } => true,
+            Pattern::Blink {
+                period_ticks,
+                on_ticks,
+            } => ticks % period_ticks.max(1) < on_ticks,
+            Pattern::Heartbeat => {
+                let phase = ticks % HEARTBEAT_PERIOD_TICKS;
+                phase == 0 || phase == 2
+            }
+            Pattern::Code { count } => {
+                let blinks = count * 2 * CODE_BLINK_TICKS;
+                let phase = ticks % (blinks + CODE_PAUSE_TICKS);
+                phase < blinks && phase % (2 * CODE_BLINK_TICKS) < CODE_BLINK_TICKS
+            }
+        };
+        if on {
+            FULL
+        } else {
+            0
+        }
     }
 
-    pub fn off(&mut self) {
-        self.pwm.set_duty(0);
+    fn is_animated(self) -> bool {
+        match self {
+            Pattern::Off | Pattern::Solid | Pattern::Dim { .. } | Pattern::FadeOut { .. } => false,
+            _ => true,
+        }
     }
 }
 
-impl ChargeLed {
-    pub fn on(&mut self) {
-        self.pwm.set_duty(BLINK_MAX_DUTY);
-        self.blinking = false;
+/// Fault codes, shown as this many blinks repeating. Zynq and SMC faults
+/// are on the status LED, battery and charger faults on the charge LED.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlinkCode {
+    /// The SMC firmware panicked, on both LEDs
+    Panic,
+    /// A Zynq rail didn't reach power-good, or dropped out
+    Rail,
+    /// The heartbeat watchdog gave up on the Zynq
+    Watchdog,
+    /// The battery is too low to power up the Zynq
+    LowBattery,
+    /// No battery, and USB can't supply enough to power up the Zynq
+    WeakSupply,
+    /// Charge LED, input overvoltage or a bad input supply
+    ChargerInput,
+    /// Charge LED, battery too hot or cold, or the charger overheated
+    ChargerTemperature,
+    /// Charge LED, battery overvoltage
+    BatteryOvervoltage,
+    /// Charge LED, the charger stopped answering on I2C, or an unknown fault
+    Charger,
+    /// Charge LED, the fuel gauge stopped answering on I2C
+    Gauge,
+    /// Charge LED, charging took longer than the safety timer allows
+    SafetyTimer,
+    /// Charge LED, the charger doesn't see a battery
+    NoBattery,
+}
+
+impl BlinkCode {
+    /// Number of blinks, only unique among the codes sharing an LED
+    pub fn count(self) -> u32 {
+        match self {
+            BlinkCode::Panic => 2,
+            BlinkCode::Rail => 3,
+            BlinkCode::Watchdog => 4,
+            BlinkCode::LowBattery => 5,
+            BlinkCode::WeakSupply => 6,
+            BlinkCode::ChargerInput => 2,
+            BlinkCode::ChargerTemperature => 3,
+            BlinkCode::BatteryOvervoltage => 4,
+            BlinkCode::Charger => 5,
+            BlinkCode::Gauge => 6,
+            BlinkCode::SafetyTimer => 7,
+            BlinkCode::NoBattery => 8,
+        }
     }
 
-    pub fn off(&mut self) {
-        self.pwm.set_duty(0);
-        self.blinking = false;
+    pub fn pattern(self) -> Pattern {
+        Pattern::Code {
+            count: self.count(),
+        }
     }
+}
+
+/// Who is asking for a pattern, lowest priority first. The highest layer
+/// with a pattern set is shown, and clearing it uncovers the one below.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    /// Steady state, like power on or charging
+    Base,
+    /// Short lived feedback, like a button press
+    Notice,
+    /// Something needs attention soon, like a low battery
+    Warning,
+    Fault,
+}
 
-    pub fn blink(&mut self) {
-        if !self.blinking {
-            self.blinking = true;
-            self.blinking_up = true;
-            self.blink_index = 0;
-            self.pwm.set_duty(BLINK_MIN_DUTY);
+const LAYER_COUNT: usize = 4;
+
+#[derive(Clone, Copy)]
+struct Active {
+    pattern: Pattern,
+    since: u32,
+    /// Tick the layer clears itself on, for flashes
+    until: Option<u32>,
+}
+
+/// What TIM2's update interrupt does to a channel every ms
+#[derive(Clone, Copy, PartialEq)]
+enum Wave {
+    Level(u16),
+    Fade {
+        from: u16,
+        to: u16,
+        elapsed_ms: u32,
+        duration_ms: u32,
+    },
+    Breathe {
+        period_ms: u32,
+        phase_ms: u32,
+    },
+}
+
+struct Channel {
+    wave: Wave,
+    /// Brightness last written to the compare register
+    shown: u16,
+    max_duty: u32,
+}
+
+impl Channel {
+    /// Advances the wave by a ms
+    fn step(&mut self) {
+        let level = match self.wave {
+            Wave::Level(level) => level,
+            Wave::Fade {
+                from,
+                to,
+                elapsed_ms,
+                duration_ms,
+            } => {
+                let elapsed_ms = elapsed_ms + 1;
+                if elapsed_ms >= duration_ms {
+                    self.wave = Wave::Level(to);
+                    to
+                } else {
+                    let delta = (to as i32 - from as i32) * elapsed_ms as i32 / duration_ms as i32;
+                    self.wave = Wave::Fade {
+                        from,
+                        to,
+                        elapsed_ms,
+                        duration_ms,
+                    };
+                    (from as i32 + delta) as u16
+                }
+            }
+            Wave::Breathe {
+                period_ms,
+                phase_ms,
+            } => {
+                let period_ms = period_ms.max(2);
+                let phase_ms = (phase_ms + 1) % period_ms;
+                self.wave = Wave::Breathe {
+                    period_ms,
+                    phase_ms,
+                };
+                // a triangle in perceived brightness, gamma does the rest
+                let half = period_ms / 2;
+                let up = if phase_ms < half {
+                    phase_ms * FULL as u32 / half
+                } else {
+                    (period_ms - phase_ms) * FULL as u32 / (period_ms - half)
+                };
+                (BREATHE_MIN + up * (FULL as u32 - BREATHE_MIN) / FULL as u32) as u16
+            }
+        };
+        self.shown = level;
+    }
+
+    fn duty(&self) -> u32 {
+        let x = self.shown.min(FULL) as u32 * (GAMMA_TABLE.len() as u32 - 1);
+        let (i, frac) = ((x / FULL as u32) as usize, x % FULL as u32);
+        let gamma = match GAMMA_TABLE.get(i + 1) {
+            Some(&next) => GAMMA_TABLE[i] + (next - GAMMA_TABLE[i]) * frac / FULL as u32,
+            None => GAMMA_TABLE[i],
+        };
+        gamma * self.max_duty / 65535
+    }
+}
+
+/// Only touched by the tick task and TIM2's interrupt, which share a
+/// priority so they never interrupt each other
+static mut CHANNELS: [Channel; 2] = [
+    Channel {
+        wave: Wave::Level(0),
+        shown: 0,
+        max_duty: 0,
+    },
+    Channel {
+        wave: Wave::Level(0),
+        shown: 0,
+        max_duty: 0,
+    },
+];
+
+/// Steps the fades and writes the compare registers, then stops the
+/// interrupt once nothing is moving
+pub fn interrupt_tim2() {
+    let tim2 = unsafe { &*TIM2::ptr() };
+    tim2.sr.modify(|_, w| w.uif().clear_bit());
+    let channels = unsafe { &mut CHANNELS };
+    let mut moving = false;
+    for (i, channel) in channels.iter_mut().enumerate() {
+        channel.step();
+        let duty = channel.duty();
+        write_duty(i, duty);
+        match channel.wave {
+            Wave::Level(_) => {}
+            _ => moving = true,
+        }
+    }
+    if !moving {
+        tim2.dier.modify(|_, w| w.uie().clear_bit());
+    }
+}
+
+fn write_duty(channel: usize, duty: u32) {
+    let tim2 = unsafe { &*TIM2::ptr() };
+    match channel {
+        STATUS_CHANNEL => tim2.ccr4.write(|w| unsafe { w.bits(duty) }),
+        _ => tim2.ccr3.write(|w| unsafe { w.bits(duty) }),
+    }
+}
+
+/// Blinks the panic code on both LEDs forever. Interrupts are off by now,
+/// so this busy waits and drives the compare registers itself.
+pub fn show_panic() -> ! {
+    let max_duty = unsafe { [CHANNELS[0].max_duty, CHANNELS[1].max_duty] };
+    let set = |on: bool| {
+        for (channel, &max_duty) in max_duty.iter().enumerate() {
+            write_duty(channel, if on { max_duty } else { 0 });
+        }
+    };
+    loop {
+        for _ in 0..BlinkCode::Panic.count() {
+            set(true);
+            delay(CODE_BLINK_TICKS * PANIC_CYCLES_PER_TICK);
+            set(false);
+            delay(CODE_BLINK_TICKS * PANIC_CYCLES_PER_TICK);
+        }
+        delay(CODE_PAUSE_TICKS * PANIC_CYCLES_PER_TICK);
+    }
+}
+
+/// Hands a channel a new wave, the interrupt picks it up within a ms
+fn start_wave(channel: usize, wave: Wave) {
+    let tim2 = unsafe { &*TIM2::ptr() };
+    unsafe {
+        CHANNELS[channel].wave = wave;
+    }
+    tim2.dier.modify(|_, w| w.uie().set_bit());
+}
+
+impl<C, P> Led<C, P> {
+    fn new(pwm: pwm::Pwm<TIM2, C, P>, channel: usize) -> Self {
+        Self {
+            _pwm: pwm,
+            channel,
+            layers: [None; LAYER_COUNT],
+            shown: None,
         }
     }
 
-    pub fn tick(&mut self, _: u32) {
-        if !self.blinking {
-            return;
+    /// Asks for `pattern` on `layer`. Setting the pattern a layer already
+    /// shows doesn't restart it, so this is fine to call every tick.
+    pub fn set(&mut self, layer: Layer, pattern: Pattern, tick: u32) {
+        let active = &mut self.layers[layer as usize];
+        match active {
+            Some(current) if current.pattern == pattern => {}
+            _ => {
+                *active = Some(Active {
+                    pattern,
+                    since: tick,
+                    until: None,
+                })
+            }
         }
-        if self.blinking_up {
-            self.blink_index += 1;
-            if self.blink_index >= BLINK_DUTY_TABLE.len() - 1 {
-                self.blinking_up = false;
+    }
+
+    /// Shows `pattern` on `layer` for `ticks`, then clears the layer
+    pub fn flash(&mut self, layer: Layer, pattern: Pattern, tick: u32, ticks: u32) {
+        self.layers[layer as usize] = Some(Active {
+            pattern,
+            since: tick,
+            until: Some(tick.wrapping_add(ticks)),
+        });
+    }
+
+    pub fn clear(&mut self, layer: Layer) {
+        self.layers[layer as usize] = None;
+    }
+
+    /// Whether the shown pattern, or a fade to it, needs the SMC awake
+    pub fn is_animated(&self) -> bool {
+        let fading = match unsafe { CHANNELS[self.channel].wave } {
+            Wave::Level(_) => false,
+            _ => true,
+        };
+        fading
+            || self
+                .top()
+                .map_or(false, |active| active.pattern.is_animated())
+    }
+
+    /// Whether a pattern shown for a while is up, which needs the SMC
+    /// awake to take it down again
+    pub fn is_flashing(&self) -> bool {
+        self.top().map_or(false, |active| active.until.is_some())
+    }
+
+    /// Hands the shown pattern to TIM2. Breathing and fades between steady
+    /// levels run there, the blink patterns are stepped here.
+    pub fn tick(&mut self, tick: u32) {
+        for layer in self.layers.iter_mut() {
+            if let Some(Active {
+                until: Some(until), ..
+            }) = *layer
+            {
+                if tick.wrapping_sub(until) < u32::max_value() / 2 {
+                    *layer = None;
+                }
             }
-        } else {
-            self.blink_index -= 1;
-            if self.blink_index == 0 {
-                self.blinking_up = true;
+        }
+        let top = self.top().copied();
+        let pattern = top.map(|active| active.pattern);
+        let changed = pattern != self.shown;
+        self.shown = pattern;
+        let wave = match pattern {
+            Some(Pattern::Breathe { period_ms }) => Wave::Breathe {
+                period_ms,
+                phase_ms: 0,
+            },
+            None | Some(Pattern::Off) | Some(Pattern::Solid) | Some(Pattern::Dim { .. }) => {
+                Wave::Fade {
+                    from: unsafe { CHANNELS[self.channel].shown },
+                    to: pattern.map_or(0, |pattern| pattern.brightness(0)),
+                    elapsed_ms: 0,
+                    duration_ms: FADE_MS,
+                }
+            }
+            Some(Pattern::FadeOut { ms }) => Wave::Fade {
+                from: unsafe { CHANNELS[self.channel].shown },
+                to: 0,
+                elapsed_ms: 0,
+                duration_ms: ms,
+            },
+            Some(pattern) => {
+                let since = top.map_or(tick, |active| active.since);
+                let level = pattern.brightness(tick.wrapping_sub(since));
+                start_wave(self.channel, Wave::Level(level));
+                return;
             }
+        };
+        if changed {
+            start_wave(self.channel, wave);
         }
-        let duty = BLINK_DUTY_TABLE[self.blink_index];
-        self.pwm.set_duty(BLINK_MIN_DUTY + duty);
+    }
+
+    fn top(&self) -> Option<&Active> {
+        self.layers.iter().rev().filter_map(Option::as_ref).next()
     }
 }
@@ -0,0 +1,368 @@
+//! SMC command protocol, carried inline on the console bridge.
+//!
+//! Lines starting with `@smc ` in either direction (USB host to Zynq, or
+//! Zynq to USB host) are taken out of the stream and handled by the SMC.
+//! Everything else passes through untouched.
+
+use crate::policy::PowerOnPolicy;
+use crate::switch::{ButtonEvent, ButtonMask};
+use crate::watchdog::Recovery;
+use core::fmt::{self, Write};
+
+pub const PREFIX: &[u8] = b"@smc ";
+const MAX_LINE_LEN: usize = 32;
+const QUEUE_LEN: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Host,
+    Zynq,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// Pulse POR with the rails up, optionally for a given number of ms
+    Reset {
+        hold_ms: Option<u32>,
+    },
+    /// Clear a latched rail fault so the Zynq may power up again
+    AckFault,
+    PowerOn,
+    /// Ask the OS to shut down, or with `force` cut power right away
+    PowerOff {
+        force: bool,
+    },
+    /// How long to wait for the OS to halt once it acknowledged a shutdown
+    ShutdownTimeout {
+        ms: u32,
+    },
+    /// From the Zynq, the OS is shutting down
+    ShutdownAck,
+    /// From the Zynq, the OS has halted and power can be cut
+    Halted,
+    /// From the Zynq, the OS is alive
+    Heartbeat,
+    /// Turn the heartbeat watchdog off, or on with the given recovery
+    Watchdog(Option<Recovery>),
+    /// Dump the recent power sequencing traces to the host, and the rail
+    /// fault that stopped the last one
+    Trace,
+    /// Dump per-rail ramp time statistics to the host
+    Stats,
+    /// Dump the fuel gauge readings to the host
+    Battery,
+    /// Choose when the Zynq powers on by itself
+    Policy(PowerOnPolicy),
+    /// Set the RTC time of day, in seconds since midnight
+    SetTime {
+        seconds: u32,
+    },
+    /// Set the daily power-on alarm, in seconds since midnight
+    SetAlarm {
+        seconds: u32,
+    },
+    /// Which switch presses go to the Zynq while it's running
+    Forward(ButtonMask),
+    /// Battery percentages to warn at, and to shut down at
+    LowBattery {
+        warn: u32,
+        critical: u32,
+    },
+    /// Shut down, then quiet the charger and gauge until USB is plugged in
+    /// or the switch gets a long press. The cell stays connected.
+    Ship,
+}
+
+/// Notifications the SMC sends to the Zynq
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Message {
+    ShutdownRequest,
+    /// A switch press, with the SMC uptime in ms when it was recognized
+    Button {
+        event: ButtonEvent,
+        at_ms: u32,
+    },
+    /// The battery dropped to the warning threshold
+    BatteryLow {
+        soc_percent: u16,
+    },
+    /// The battery is critical, a shutdown request follows
+    BatteryCritical,
+}
+
+/// Longest line a message may format to, prefix and newline included
+pub const MAX_MESSAGE_LEN: usize = 48;
+
+impl Message {
+    /// Formats the message as a full line into `buf`, returns its length
+    pub fn write(&self, buf: &mut [u8; MAX_MESSAGE_LEN]) -> usize {
+        let mut writer = LineWriter { buf, len: 0 };
+        writer.buf[..PREFIX.len()].copy_from_slice(PREFIX);
+        writer.len = PREFIX.len();
+        let _ = match self {
+            Message::ShutdownRequest => writer.write_str("shutdown"),
+            Message::Button { event, at_ms } => {
+                write!(writer, "button {} {}", event.name(), at_ms)
+            }
+            Message::BatteryLow { soc_percent } => write!(writer, "battery-low {}", soc_percent),
+            Message::BatteryCritical => writer.write_str("battery-critical"),
+        };
+        let _ = writer.write_str("\n");
+        writer.len
+    }
+}
+
+/// fmt::Write into a fixed buffer, truncating what doesn't fit
+struct LineWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Write for LineWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        let len = bytes.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        if len == bytes.len() {
+            Ok(())
+        } else {
+            Err(fmt::Error)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Request {
+    pub source: Source,
+    pub command: Command,
+}
+
+impl Command {
+    pub fn parse(line: &[u8]) -> Option<Command> {
+        let mut words = line.split(|&b| b == b' ').filter(|w| !w.is_empty());
+        match words.next()? {
+            b"reset" => Some(Command::Reset {
+                hold_ms: words.next().and_then(parse_u32),
+            }),
+            b"ack" => Some(Command::AckFault),
+            b"on" => Some(Command::PowerOn),
+            b"off" => Some(Command::PowerOff {
+                force: words.next() == Some(b"force"),
+            }),
+            b"shutdown-timeout" => Some(Command::ShutdownTimeout {
+                ms: words.next().and_then(parse_u32)?,
+            }),
+            b"shutdown-ack" => Some(Command::ShutdownAck),
+            b"halted" => Some(Command::Halted),
+            b"hb" => Some(Command::Heartbeat),
+            b"trace" => Some(Command::Trace),
+            b"stats" => Some(Command::Stats),
+            b"battery" => Some(Command::Battery),
+            b"ship" => Some(Command::Ship),
+            b"policy" => match words.next()? {
+                b"manual" => Some(Command::Policy(PowerOnPolicy::Manual)),
+                b"usb" => Some(Command::Policy(PowerOnPolicy::UsbPower)),
+                b"restore" => Some(Command::Policy(PowerOnPolicy::RestoreLast)),
+                b"alarm" => Some(Command::Policy(PowerOnPolicy::Alarm)),
+                _ => None,
+            },
+            b"time" => Some(Command::SetTime {
+                seconds: parse_time(words.next()?)?,
+            }),
+            b"alarm" => Some(Command::SetAlarm {
+                seconds: parse_time(words.next()?)?,
+            }),
+            b"low-battery" => {
+                let warn = words.next().and_then(parse_u32)?;
+                let critical = words.next().and_then(parse_u32)?;
+                if critical >= warn || warn > 100 {
+                    return None;
+                }
+                Some(Command::LowBattery { warn, critical })
+            }
+            b"forward" => {
+                let mut mask = ButtonMask::NONE;
+                for word in words {
+                    mask = match word {
+                        b"none" => ButtonMask::NONE,
+                        b"short" => mask.with(ButtonEvent::ShortPress),
+                        b"double" => mask.with(ButtonEvent::DoublePress),
+                        b"long" => mask.with(ButtonEvent::LongPress),
+                        _ => return None,
+                    };
+                }
+                Some(Command::Forward(mask))
+            }
+            b"watchdog" => match words.next()? {
+                b"off" => Some(Command::Watchdog(None)),
+                b"reset" => Some(Command::Watchdog(Some(Recovery::Reset))),
+                b"cycle" => Some(Command::Watchdog(Some(Recovery::PowerCycle))),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+fn parse_u32(word: &[u8]) -> Option<u32> {
+    let mut value: u32 = 0;
+    for &b in word {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        value = value.checked_mul(10)?.checked_add((b - b'0') as u32)?;
+    }
+    Some(value)
+}
+
+/// Parses HH:MM:SS into seconds since midnight
+fn parse_time(word: &[u8]) -> Option<u32> {
+    let mut fields = word.split(|&b| b == b':').map(parse_u32);
+    let h = fields.next()??;
+    let m = fields.next()??;
+    let s = fields.next()??;
+    if fields.next().is_some() || h >= 24 || m >= 60 || s >= 60 {
+        return None;
+    }
+    Some(h * 3600 + m * 60 + s)
+}
+
+fn is_line_end(b: u8) -> bool {
+    b == b'\n' || b == b'\r'
+}
+
+#[derive(Clone, Copy)]
+enum FilterState {
+    /// At the start of a line, with this many bytes of the prefix matched
+    LineStart(usize),
+    Passthrough,
+    Capturing,
+}
+
+/// Splits SMC command lines out of a byte stream
+pub struct LineFilter {
+    state: FilterState,
+    line: [u8; MAX_LINE_LEN],
+    len: usize,
+}
+
+impl LineFilter {
+    pub const fn new() -> Self {
+        Self {
+            state: FilterState::LineStart(0),
+            line: [0; MAX_LINE_LEN],
+            len: 0,
+        }
+    }
+
+    /// Feeds `data` through the filter. Bytes that aren't part of a command
+    /// line go to `forward`, and each complete command line (without the
+    /// prefix) goes to `command`.
+    ///
+    /// Bytes that might be the start of a prefix are held back until the
+    /// prefix either matches or doesn't, which may be in a later call.
+    pub fn process<F, C>(&mut self, data: &[u8], mut forward: F, mut command: C)
+    where
+        F: FnMut(&[u8]),
+        C: FnMut(&[u8]),
+    {
+        let mut run_start = 0;
+        for (i, &b) in data.iter().enumerate() {
+            self.state = match self.state {
+                FilterState::LineStart(matched) if b == PREFIX[matched] => {
+                    if matched == 0 && i > run_start {
+                        forward(&data[run_start..i]);
+                    }
+                    if matched + 1 == PREFIX.len() {
+                        self.len = 0;
+                        FilterState::Capturing
+                    } else {
+                        FilterState::LineStart(matched + 1)
+                    }
+                }
+                FilterState::LineStart(matched) => {
+                    if matched > 0 {
+                        // false alarm, let the held back bytes through
+                        forward(&PREFIX[..matched]);
+                        run_start = i;
+                    }
+                    if is_line_end(b) {
+                        FilterState::LineStart(0)
+                    } else {
+                        FilterState::Passthrough
+                    }
+                }
+                FilterState::Passthrough => {
+                    if is_line_end(b) {
+                        FilterState::LineStart(0)
+                    } else {
+                        FilterState::Passthrough
+                    }
+                }
+                FilterState::Capturing => {
+                    if is_line_end(b) {
+                        command(&self.line[..self.len]);
+                        run_start = i + 1;
+                        FilterState::LineStart(0)
+                    } else {
+                        // overlong lines are truncated, and won't parse
+                        if self.len < MAX_LINE_LEN {
+                            self.line[self.len] = b;
+                            self.len += 1;
+                        }
+                        FilterState::Capturing
+                    }
+                }
+            };
+        }
+        match self.state {
+            FilterState::Capturing => {}
+            FilterState::LineStart(matched) if matched > 0 => {}
+            _ => {
+                if data.len() > run_start {
+                    forward(&data[run_start..]);
+                }
+            }
+        }
+    }
+}
+
+/// Small FIFO of parsed commands waiting for the tick task
+pub struct CommandQueue {
+    entries: [Option<Request>; QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl CommandQueue {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; QUEUE_LEN],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Parses and queues a command line, dropping it if the queue is full
+    /// or the line isn't a command we know
+    pub fn push_line(&mut self, source: Source, line: &[u8]) {
+        if self.len == QUEUE_LEN {
+            return;
+        }
+        if let Some(command) = Command::parse(line) {
+            self.entries[(self.head + self.len) % QUEUE_LEN] = Some(Request { source, command });
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Request> {
+        if self.len == 0 {
+            return None;
+        }
+        let request = self.entries[self.head].take();
+        self.head = (self.head + 1) % QUEUE_LEN;
+        self.len -= 1;
+        request
+    }
+}
@@ -0,0 +1,107 @@
+use crate::settings::{self, Slot};
+use crate::stc3115::Reading;
+
+/// Below this the Zynq gets shut down, whatever the state of charge says
+const CRITICAL_MV: u16 = 3300;
+/// Below this the battery counts as low, whatever the state of charge says
+const LOW_MV: u16 = 3500;
+/// How far above the thresholds the battery has to get back before the
+/// level goes back up, in tenths of a percent and mV
+const HYSTERESIS_SOC: u16 = 20;
+const HYSTERESIS_MV: u16 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Level {
+    Normal,
+    /// Time to warn the user
+    Low,
+    /// Time to shut down, and too low to power up again
+    Critical,
+}
+
+/// Persisted low battery thresholds, and where the battery is against them
+pub struct LowBattery {
+    /// Warning threshold in percent
+    warn: u32,
+    /// Shutdown threshold in percent
+    critical: u32,
+    level: Level,
+    changed: bool,
+    critical_since: Option<u32>,
+}
+
+impl LowBattery {
+    pub fn load() -> Self {
+        Self {
+            warn: settings::read(Slot::LowBatteryWarn),
+            critical: settings::read(Slot::LowBatteryCritical),
+            level: Level::Normal,
+            changed: false,
+            critical_since: None,
+        }
+    }
+
+    pub fn set_thresholds(&mut self, warn: u32, critical: u32) {
+        self.warn = warn;
+        self.critical = critical;
+        settings::write(Slot::LowBatteryWarn, warn);
+        settings::write(Slot::LowBatteryCritical, critical);
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// Ticks since the battery went critical, if it is
+    pub fn critical_for(&self, tick: u32) -> Option<u32> {
+        self.critical_since.map(|since| tick.wrapping_sub(since))
+    }
+
+    /// The level, if it changed since the last call
+    pub fn take_change(&mut self) -> Option<Level> {
+        if self.changed {
+            self.changed = false;
+            Some(self.level)
+        } else {
+            None
+        }
+    }
+
+    /// Classifies a new gauge reading. Without one the level stays put,
+    /// a missing gauge shows up as its own fault.
+    pub fn update(&mut self, reading: Option<&Reading>, tick: u32) {
+        let reading = match reading {
+            Some(reading) => reading,
+            None => return,
+        };
+        // leaving a level takes getting back over its threshold by the
+        // hysteresis, so a sagging cell doesn't flap between levels
+        let above = |soc: u16, mv: u16, leaving: bool| {
+            let (soc_margin, mv_margin) = if leaving {
+                (HYSTERESIS_SOC, HYSTERESIS_MV)
+            } else {
+                (0, 0)
+            };
+            reading.soc > soc + soc_margin && reading.voltage_mv > mv + mv_margin
+        };
+        let critical_soc = (self.critical * 10) as u16;
+        let warn_soc = (self.warn * 10) as u16;
+        let level = if reading.voltage_alarm
+            || !above(critical_soc, CRITICAL_MV, self.level == Level::Critical)
+        {
+            Level::Critical
+        } else if !above(warn_soc, LOW_MV, self.level != Level::Normal) {
+            Level::Low
+        } else {
+            Level::Normal
+        };
+        if level != self.level {
+            self.level = level;
+            self.changed = true;
+            self.critical_since = match level {
+                Level::Critical => Some(tick),
+                _ => None,
+            };
+        }
+    }
+}
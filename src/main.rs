@@ -1,31 +1,75 @@
 #![no_std]
 #![no_main]
 
-extern crate panic_halt;
-
 mod battery;
+mod bc12;
+mod bq24250;
+mod health;
+mod i2c_bus;
+mod jeita;
 mod leds;
+mod link;
+mod lowbat;
+mod policy;
 mod power;
+mod rtc;
+mod settings;
+mod stc3115;
 mod switch;
+mod time;
+mod trace;
 mod uart;
 mod usb;
+mod watchdog;
 mod zynq;
 
 use hal::{exti::Exti, prelude::*, rcc, syscfg::SYSCFG};
+use rtfm::Mutex;
 use stm32l0::stm32l0x3 as pac;
 use stm32l0xx_hal as hal;
 
+/// How long the status LED takes to go dark once the rails start going down
+const POWER_DOWN_FADE_MS: u32 = 1000;
+/// Ticks a critical battery gives the OS to shut down before power is cut
+const CRITICAL_SHUTDOWN_TICKS: u32 = 100;
+/// Ticks the reason code shows after a refused power-up
+const REFUSED_NOTICE_TICKS: u32 = 30;
+/// Current the board can draw at full load, which USB has to be able to
+/// supply when there's no battery to make up the difference
+const BOARD_SUPPLY_MA: u32 = 900;
+/// Ticks the state of charge shows after a short press while off
+const BATTERY_CHECK_TICKS: u32 = 50;
+/// Ticks a fault code shows while the Zynq is off, or after a press
+const FAULT_NOTICE_TICKS: u32 = 100;
+
+const FAST_BLINK: leds::Pattern = leds::Pattern::Blink {
+    period_ticks: 2,
+    on_ticks: 1,
+};
+
 #[rtfm::app(device=stm32l0::stm32l0x3, peripherals=true)]
 const APP: () = {
     struct Resources {
         #[init(0)]
         tick: u32,
+        /// A short press while off asked for the state of charge
+        #[init(false)]
+        battery_check: bool,
+        /// Fault code last flashed while the Zynq was off
+        #[init(None)]
+        fault_shown: Option<leds::BlinkCode>,
+        /// A power-up waiting on the supply being known
+        power_up_request: Option<PowerUpRequest>,
         status_led: leds::StatusLed,
         switch: switch::SwitchState,
         zynq: zynq::ZynqState,
         usb: usb::UsbState,
         uart: uart::UartState,
         battery: battery::BatteryState,
+        battery_monitor: battery::BatteryMonitor,
+        watchdog: watchdog::Watchdog,
+        policy: policy::PowerPolicy,
+        low_battery: lowbat::LowBattery,
     }
 
     #[init]
@@ -67,14 +111,14 @@ const APP: () = {
             gpioc.pc6.into_floating_input(),
             gpioc.pc7.into_floating_input(),
             gpioc.pc8.into_push_pull_output(),
+            &mut exti,
+            &mut syscfg,
         );
-        let battery = battery::BatteryState::new(
-            peripherals.I2C1,
-            gpiob.pb8,
-            gpiob.pb9,
-            charge_led,
-            &mut rcc,
-        );
+        // the battery reads its learned state from the settings
+        settings::init();
+        let battery_monitor =
+            battery::BatteryMonitor::new(peripherals.I2C1, gpiob.pb8, gpiob.pb9, &mut rcc);
+        let battery = battery::BatteryState::new(charge_led);
         let usb = usb::UsbState::new(
             peripherals.USB,
             gpioa.pa11,
@@ -93,23 +137,45 @@ const APP: () = {
         );
 
         power::init();
+        rtc::init(&mut exti);
+
+        let policy = policy::PowerPolicy::load();
+        rtc::set_alarm(policy.alarm());
 
         init::LateResources {
+            power_up_request: if policy.power_on_at_boot() {
+                Some(PowerUpRequest::Policy)
+            } else {
+                None
+            },
             status_led,
             switch,
             zynq,
             usb,
             uart,
             battery,
+            battery_monitor,
+            watchdog: watchdog::Watchdog::new(),
+            policy,
+            low_battery: lowbat::LowBattery::load(),
         }
     }
 
-    #[idle(resources=[uart, battery, usb])]
+    #[idle(resources=[uart, battery, battery_monitor, usb])]
     fn idle(mut cx: idle::Context) -> ! {
         loop {
-            cx.resources
-                .battery
-                .lock(|battery| battery.update_if_needed());
+            // the locks only cover the hand-over, the I2C transfers run
+            // with the interrupts free to preempt them
+            if let Some(job) = cx.resources.battery.lock(|battery| battery.take_job()) {
+                let monitor = &mut *cx.resources.battery_monitor;
+                let report = monitor.update(&job);
+                cx.resources
+                    .battery
+                    .lock(|battery| battery.publish(&job, report));
+                if job.write_status() {
+                    cx.resources.usb.lock(|usb| monitor.write_status(usb).ok());
+                }
+            }
             if power::sleep_if_needed() {
                 cx.resources.usb.lock(|usb| usb.reset());
             }
@@ -132,27 +198,485 @@ const APP: () = {
         cx.resources.uart.interrupt_lpuart(&mut cx.resources.usb);
     }
 
-    #[task(binds=SysTick, priority=2, resources=[tick, zynq, battery])]
-    fn tick_100ms(cx: tick_100ms::Context) {
+    #[task(
+        binds=SysTick,
+        priority=2,
+        resources=[
+            tick,
+            battery_check,
+            fault_shown,
+            power_up_request,
+            switch,
+            zynq,
+            battery,
+            status_led,
+            uart,
+            usb,
+            watchdog,
+            policy,
+            low_battery
+        ]
+    )]
+    fn tick_100ms(mut cx: tick_100ms::Context) {
         *cx.resources.tick += 1;
+        let tick = *cx.resources.tick;
+        while let Some(request) = cx.resources.uart.lock(|uart| uart.take_command()) {
+            if let Some(reply) = handle_command(
+                request,
+                tick,
+                cx.resources.zynq,
+                cx.resources.switch,
+                cx.resources.watchdog,
+                cx.resources.policy,
+                cx.resources.low_battery,
+                cx.resources.battery,
+                cx.resources.power_up_request,
+            ) {
+                send_reply(
+                    reply,
+                    cx.resources.zynq,
+                    &mut cx.resources.uart,
+                    &mut cx.resources.usb,
+                );
+            }
+        }
+        if let Some(event) = cx.resources.switch.tick(tick) {
+            // any press while off shows the fault code again
+            if !cx.resources.zynq.is_power_on() {
+                *cx.resources.fault_shown = None;
+            }
+            let forward = cx.resources.switch.forwards(event);
+            if let Some(reply) = handle_button(
+                event,
+                forward,
+                tick,
+                cx.resources.zynq,
+                cx.resources.policy,
+                cx.resources.battery,
+                cx.resources.battery_check,
+                cx.resources.power_up_request,
+            ) {
+                send_reply(
+                    reply,
+                    cx.resources.zynq,
+                    &mut cx.resources.uart,
+                    &mut cx.resources.usb,
+                );
+            }
+        }
+        let (budget, detecting) = cx.resources.usb.lock(|usb| {
+            usb.tick();
+            (usb.input_budget(), usb.is_detecting())
+        });
+        cx.resources.battery.set_input_budget(budget);
         cx.resources.battery.tick(*cx.resources.tick);
+        let low_battery = &mut *cx.resources.low_battery;
+        low_battery.update(cx.resources.battery.reading(), tick);
+        cx.resources
+            .zynq
+            .set_battery_low(low_battery.level() == lowbat::Level::Critical);
+        // a good reading proves there's a cell, otherwise USB has to carry
+        // the board on its own
+        cx.resources.zynq.set_supply_ok(
+            cx.resources.battery.reading().is_some() || budget.milliamps() >= BOARD_SUPPLY_MA,
+        );
+        // a gauge still on its first conversion, or a port still being
+        // detected, could yet show the supply is fine
+        let supply_known = !cx.resources.battery.reading_pending() && !detecting;
+        let request = &mut *cx.resources.power_up_request;
+        if let (Some(kind), true) = (*request, supply_known) {
+            match (kind, cx.resources.zynq.power_up()) {
+                (PowerUpRequest::Manual, Ok(())) => {
+                    cx.resources.watchdog.rearm();
+                    *request = None;
+                }
+                (PowerUpRequest::Policy, Ok(())) => *request = None,
+                (PowerUpRequest::Manual, Err(error)) => {
+                    let code = match error {
+                        zynq::PowerUpError::LowBattery => Some(leds::BlinkCode::LowBattery),
+                        zynq::PowerUpError::WeakSupply => Some(leds::BlinkCode::WeakSupply),
+                        zynq::PowerUpError::FaultLatched => None,
+                    };
+                    if let Some(code) = code {
+                        cx.resources.status_led.flash(
+                            leds::Layer::Notice,
+                            code.pattern(),
+                            tick,
+                            REFUSED_NOTICE_TICKS,
+                        );
+                    }
+                    *request = None;
+                }
+                (PowerUpRequest::Policy, Err(zynq::PowerUpError::FaultLatched)) => *request = None,
+                // tried again each tick, the cell may charge or a
+                // stronger port turn up
+                (PowerUpRequest::Policy, Err(_)) => {}
+            }
+        }
+        power::set_power_up_waiting(request.is_some() && !supply_known);
+        let running = cx.resources.zynq.status() == zynq::Status::Running;
+        match low_battery.take_change() {
+            Some(lowbat::Level::Low) if running => {
+                let soc_percent = cx
+                    .resources
+                    .battery
+                    .reading()
+                    .map_or(0, |reading| reading.soc / 10);
+                cx.resources
+                    .uart
+                    .lock(|uart| uart.send(link::Message::BatteryLow { soc_percent }));
+            }
+            Some(lowbat::Level::Critical) => {
+                if running {
+                    cx.resources
+                        .uart
+                        .lock(|uart| uart.send(link::Message::BatteryCritical));
+                }
+                if cx.resources.zynq.request_shutdown(tick) {
+                    cx.resources
+                        .uart
+                        .lock(|uart| uart.send(link::Message::ShutdownRequest));
+                }
+            }
+            _ => {}
+        }
+        // don't let a slow shutdown run the cell flat
+        if low_battery
+            .critical_for(tick)
+            .map_or(false, |ticks| ticks >= CRITICAL_SHUTDOWN_TICKS)
+        {
+            cx.resources.zynq.power_down();
+        }
         cx.resources.zynq.tick(*cx.resources.tick);
+        let status = cx.resources.zynq.status();
+        cx.resources.policy.tick(status);
+        match cx.resources.watchdog.tick(tick, status) {
+            Some(watchdog::Action::Reset) => {
+                cx.resources.zynq.reset(tick, zynq::RESET_HOLD_TICKS);
+            }
+            Some(watchdog::Action::PowerDown) | Some(watchdog::Action::GiveUp) => {
+                cx.resources.zynq.power_down();
+            }
+            Some(watchdog::Action::PowerUp) => {
+                cx.resources.zynq.power_up().ok();
+            }
+            None => {}
+        }
+        // whatever boots next has to ask for presses again
+        if !cx.resources.zynq.is_power_on() {
+            cx.resources.switch.set_forward(switch::ButtonMask::NONE);
+        }
+        // only once the rails are all the way down
+        let off = match cx.resources.zynq.status() {
+            zynq::Status::Off | zynq::Status::Fault => true,
+            _ => false,
+        };
+        if off && cx.resources.battery.ship_mode_pending() {
+            // the alarm would wake us, and maybe the Zynq
+            rtc::set_alarm(None);
+            *cx.resources.power_up_request = None;
+            cx.resources.battery.enter_ship_mode(tick);
+        }
+        let status_led = &mut *cx.resources.status_led;
+        let zynq = &*cx.resources.zynq;
+        status_led.set(
+            leds::Layer::Base,
+            status_pattern(zynq.status(), zynq.os_ready()),
+            tick,
+        );
+        let code = if zynq.fault().is_some() {
+            Some(leds::BlinkCode::Rail)
+        } else if cx.resources.watchdog.gave_up() {
+            Some(leds::BlinkCode::Watchdog)
+        } else {
+            None
+        };
+        // only for a while when off, like the warnings, a code left up
+        // would keep the SMC out of Stop
+        let fault_shown = &mut *cx.resources.fault_shown;
+        match code {
+            Some(code) if zynq.is_power_on() => {
+                status_led.set(leds::Layer::Fault, code.pattern(), tick);
+                *fault_shown = None;
+            }
+            Some(code) => {
+                if *fault_shown != Some(code) {
+                    status_led.flash(leds::Layer::Fault, code.pattern(), tick, FAULT_NOTICE_TICKS);
+                }
+                *fault_shown = Some(code);
+            }
+            None => {
+                status_led.clear(leds::Layer::Fault);
+                *fault_shown = None;
+            }
+        }
+        // only while on, a warning mustn't keep the SMC awake when off
+        let warning = match cx.resources.low_battery.level() {
+            lowbat::Level::Low => Some(leds::Pattern::Heartbeat),
+            lowbat::Level::Critical => Some(FAST_BLINK),
+            lowbat::Level::Normal => None,
+        };
+        match warning {
+            Some(pattern) if zynq.is_power_on() => {
+                status_led.set(leds::Layer::Warning, pattern, tick)
+            }
+            _ => status_led.clear(leds::Layer::Warning),
+        }
+        let battery_check = &mut *cx.resources.battery_check;
+        if *battery_check && !cx.resources.battery.update_pending() {
+            *battery_check = false;
+            cx.resources
+                .battery
+                .show_charge_level(tick, BATTERY_CHECK_TICKS);
+        }
+        status_led.tick(tick);
+        // stays awake until the fresh reading is in and has been shown
+        power::set_leds_active(
+            status_led.is_animated() || cx.resources.battery.is_showing_level() || *battery_check,
+        );
     }
 
-    #[task(binds = EXTI4_15, priority=2, resources=[usb])]
+    #[task(
+        binds = EXTI4_15,
+        priority=2,
+        resources=[tick, zynq, usb, policy, battery, power_up_request]
+    )]
     fn interrupt_exti15_4(mut cx: interrupt_exti15_4::Context) {
-        cx.resources.usb.lock(|usb| usb.handle_detect_interrupt());
+        cx.resources
+            .zynq
+            .handle_power_good_interrupt(*cx.resources.tick);
+        let attached = cx.resources.usb.lock(|usb| usb.handle_detect_interrupt());
+        let battery = &*cx.resources.battery;
+        if attached && (battery.in_ship_mode() || battery.ship_mode_pending()) {
+            exit_ship_mode(cx.resources.battery, cx.resources.policy);
+        }
+        if attached && cx.resources.policy.power_on_at_usb_attach() {
+            // the tick powers up once detection says what the port can do
+            request_power_up(cx.resources.power_up_request, PowerUpRequest::Policy);
+        }
+    }
+
+    #[task(binds = RTC, priority=2, resources=[policy, power_up_request])]
+    fn interrupt_rtc(cx: interrupt_rtc::Context) {
+        if rtc::alarm_fired() && cx.resources.policy.power_on_at_alarm() {
+            request_power_up(cx.resources.power_up_request, PowerUpRequest::Policy);
+        }
     }
 
-    #[task(binds = EXTI0_1, priority=2, resources=[switch, status_led, zynq])]
+    #[task(binds = TIM2, priority=2)]
+    fn interrupt_tim2(_: interrupt_tim2::Context) {
+        leds::interrupt_tim2();
+    }
+
+    #[task(binds = EXTI0_1, priority=2, resources=[tick, switch])]
     fn interrupt_exti0_1(cx: interrupt_exti0_1::Context) {
-        if cx.resources.switch.was_toggled() {
-            cx.resources.zynq.power_toggle();
-            if cx.resources.zynq.is_power_on() {
-                cx.resources.status_led.on();
-            } else {
-                cx.resources.status_led.off();
+        cx.resources.switch.handle_interrupt(*cx.resources.tick);
+    }
+};
+
+/// Acts on a classified switch press, or passes it on to the OS if
+/// `forward` is set and the Zynq is running
+fn handle_button(
+    event: switch::ButtonEvent,
+    forward: bool,
+    tick: u32,
+    zynq: &mut zynq::ZynqState,
+    policy: &policy::PowerPolicy,
+    battery: &mut battery::BatteryState,
+    battery_check: &mut bool,
+    power_up_request: &mut Option<PowerUpRequest>,
+) -> Option<Reply> {
+    if zynq.fault_latched() {
+        // the first press after a rail dropout only clears the fault
+        zynq.acknowledge_fault();
+        return None;
+    }
+    if battery.in_ship_mode() {
+        // anything short of a long press goes back to sleep
+        if event != switch::ButtonEvent::LongPress {
+            return None;
+        }
+        exit_ship_mode(battery, policy);
+    }
+    if forward && zynq.status() == zynq::Status::Running {
+        let at_ms = tick.wrapping_mul(time::TICK_US / 1000);
+        return Some(Reply::Zynq(link::Message::Button { event, at_ms }));
+    }
+    match (zynq.is_power_on(), event) {
+        // safety override, don't wait on the OS
+        (true, switch::ButtonEvent::VeryLongPress) => zynq.power_down(),
+        // a warm reset cuts the OS off as hard as pulling power, so it's
+        // only for the host's `reset`, an OS that wants the gesture can
+        // have it forwarded
+        (true, switch::ButtonEvent::DoublePress) => {}
+        (true, _) => {
+            if zynq.request_shutdown(tick) {
+                return Some(Reply::Zynq(link::Message::ShutdownRequest));
             }
         }
+        (false, switch::ButtonEvent::VeryLongPress) => {}
+        (false, switch::ButtonEvent::ShortPress) => {
+            // the reading may be from before the SMC went to sleep
+            battery.request_update();
+            *battery_check = true;
+        }
+        (false, _) => request_power_up(power_up_request, PowerUpRequest::Manual),
     }
-};
+    None
+}
+
+fn send_reply(
+    reply: Reply,
+    zynq: &zynq::ZynqState,
+    uart: &mut impl Mutex<T = uart::UartState>,
+    usb: &mut impl Mutex<T = usb::UsbState>,
+) {
+    match reply {
+        Reply::Zynq(message) => uart.lock(|uart| uart.send(message)),
+        Reply::Traces => {
+            usb.lock(|usb| zynq.write_traces(usb).ok());
+        }
+        Reply::Stats => {
+            usb.lock(|usb| zynq.write_stats(usb).ok());
+        }
+    }
+}
+
+/// How the status LED shows where the Zynq is at
+fn status_pattern(status: zynq::Status, os_ready: bool) -> leds::Pattern {
+    match status {
+        zynq::Status::PoweringUp | zynq::Status::Resetting => leds::Pattern::Blink {
+            period_ticks: 2,
+            on_ticks: 1,
+        },
+        // rails are up, the OS hasn't said anything yet
+        zynq::Status::Running if !os_ready => leds::Pattern::Blink {
+            period_ticks: 10,
+            on_ticks: 5,
+        },
+        zynq::Status::Running => leds::Pattern::Solid,
+        zynq::Status::ShuttingDown => leds::Pattern::Breathe {
+            period_ms: leds::BREATHE_PERIOD_MS,
+        },
+        zynq::Status::PoweringDown | zynq::Status::Off | zynq::Status::Fault => {
+            leds::Pattern::FadeOut {
+                ms: POWER_DOWN_FADE_MS,
+            }
+        }
+    }
+}
+
+/// Who asked for a power-up that's waiting on the supply being known
+#[derive(Clone, Copy, PartialEq)]
+enum PowerUpRequest {
+    /// Boot, USB attach or the alarm, going by the policy. Kept until it
+    /// goes through, a refusal may not last.
+    Policy,
+    /// The switch or the host, which get the refusal shown once and
+    /// start the watchdog over
+    Manual,
+}
+
+/// Holds a power-up for the tick to carry out, a manual one taking over
+/// from the policy
+fn request_power_up(request: &mut Option<PowerUpRequest>, kind: PowerUpRequest) {
+    if *request != Some(PowerUpRequest::Manual) {
+        *request = Some(kind);
+    }
+}
+
+/// What has to go out after handling a command
+enum Reply {
+    Zynq(link::Message),
+    Traces,
+    Stats,
+}
+
+/// Acts on a command from the host or the Zynq, returning what needs to be
+/// sent back, if anything
+fn handle_command(
+    request: link::Request,
+    tick: u32,
+    zynq: &mut zynq::ZynqState,
+    switch: &mut switch::SwitchState,
+    watchdog: &mut watchdog::Watchdog,
+    policy: &mut policy::PowerPolicy,
+    low_battery: &mut lowbat::LowBattery,
+    battery: &mut battery::BatteryState,
+    power_up_request: &mut Option<PowerUpRequest>,
+) -> Option<Reply> {
+    // any command from the OS means it's done booting
+    if request.source == link::Source::Zynq {
+        zynq.os_alive();
+    }
+    match request.command {
+        link::Command::Reset { hold_ms } => {
+            let hold_ticks = hold_ms.map_or(zynq::RESET_HOLD_TICKS, ms_to_ticks);
+            zynq.reset(tick, hold_ticks);
+        }
+        link::Command::AckFault => zynq.acknowledge_fault(),
+        link::Command::PowerOn => {
+            if battery.in_ship_mode() || battery.ship_mode_pending() {
+                exit_ship_mode(battery, policy);
+            }
+            request_power_up(power_up_request, PowerUpRequest::Manual);
+        }
+        link::Command::PowerOff { force: true } => zynq.power_down(),
+        link::Command::PowerOff { force: false } => {
+            if zynq.request_shutdown(tick) {
+                return Some(Reply::Zynq(link::Message::ShutdownRequest));
+            }
+        }
+        link::Command::ShutdownTimeout { ms } => zynq.set_shutdown_timeout(ms_to_ticks(ms)),
+        // only the OS itself gets to say it's alive or going down
+        link::Command::ShutdownAck if request.source == link::Source::Zynq => {
+            zynq.shutdown_acknowledged(tick)
+        }
+        link::Command::Halted if request.source == link::Source::Zynq => zynq.halted(),
+        link::Command::Heartbeat if request.source == link::Source::Zynq => {
+            watchdog.heartbeat(tick)
+        }
+        link::Command::ShutdownAck | link::Command::Halted | link::Command::Heartbeat => {}
+        link::Command::Watchdog(Some(recovery)) => watchdog.enable(recovery),
+        link::Command::Watchdog(None) => watchdog.disable(),
+        link::Command::Trace => return Some(Reply::Traces),
+        link::Command::Stats => return Some(Reply::Stats),
+        // written by idle once it has read the charger and gauge
+        link::Command::Battery => battery.request_status(),
+        link::Command::Policy(power_on_policy) => {
+            policy.set_policy(power_on_policy);
+            rtc::set_alarm(policy.alarm());
+        }
+        link::Command::SetTime { seconds } => rtc::set_time(seconds),
+        link::Command::SetAlarm { seconds } => {
+            policy.set_alarm(seconds);
+            rtc::set_alarm(policy.alarm());
+        }
+        link::Command::Forward(mask) => switch.set_forward(mask),
+        link::Command::LowBattery { warn, critical } => low_battery.set_thresholds(warn, critical),
+        link::Command::Ship => {
+            // the OS shuts down first like for `off`, the tick takes it
+            // from there once the rails are down
+            battery.request_ship_mode();
+            if zynq.request_shutdown(tick) {
+                return Some(Reply::Zynq(link::Message::ShutdownRequest));
+            }
+        }
+    }
+    None
+}
+
+fn exit_ship_mode(battery: &mut battery::BatteryState, policy: &policy::PowerPolicy) {
+    battery.exit_ship_mode();
+    rtc::set_alarm(policy.alarm());
+}
+
+fn ms_to_ticks(ms: u32) -> u32 {
+    ms / 100 + (ms % 100 != 0) as u32
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    leds::show_panic()
+}
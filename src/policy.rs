@@ -0,0 +1,99 @@
+use crate::settings::{self, Slot};
+use crate::zynq::Status;
+
+/// When the Zynq powers on without anyone pressing the switch
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerOnPolicy {
+    /// Only on a switch press or a host command
+    Manual,
+    /// Whenever USB power shows up
+    UsbPower,
+    /// After an SMC reset or brownout, back to how it was before
+    RestoreLast,
+    /// At the daily RTC alarm
+    Alarm,
+}
+
+impl PowerOnPolicy {
+    fn from_u32(value: u32) -> Self {
+        match value {
+            1 => PowerOnPolicy::UsbPower,
+            2 => PowerOnPolicy::RestoreLast,
+            3 => PowerOnPolicy::Alarm,
+            _ => PowerOnPolicy::Manual,
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            PowerOnPolicy::Manual => 0,
+            PowerOnPolicy::UsbPower => 1,
+            PowerOnPolicy::RestoreLast => 2,
+            PowerOnPolicy::Alarm => 3,
+        }
+    }
+}
+
+/// Persisted automatic power-on policy, and the state it needs
+pub struct PowerPolicy {
+    policy: PowerOnPolicy,
+    last_power_on: bool,
+}
+
+impl PowerPolicy {
+    pub fn load() -> Self {
+        Self {
+            policy: PowerOnPolicy::from_u32(settings::read(Slot::PowerOnPolicy)),
+            last_power_on: settings::read(Slot::LastPowerOn) != 0,
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: PowerOnPolicy) {
+        self.policy = policy;
+        settings::write(Slot::PowerOnPolicy, policy.to_u32());
+        // start tracking from a clean slate
+        self.last_power_on = false;
+        settings::write(Slot::LastPowerOn, 0);
+    }
+
+    /// Daily alarm time in seconds since midnight, if the policy uses one
+    pub fn alarm(&self) -> Option<u32> {
+        match self.policy {
+            PowerOnPolicy::Alarm => Some(settings::read(Slot::AlarmTime)),
+            _ => None,
+        }
+    }
+
+    pub fn set_alarm(&mut self, seconds: u32) {
+        settings::write(Slot::AlarmTime, seconds);
+    }
+
+    pub fn power_on_at_boot(&self) -> bool {
+        self.policy == PowerOnPolicy::RestoreLast && self.last_power_on
+    }
+
+    pub fn power_on_at_usb_attach(&self) -> bool {
+        self.policy == PowerOnPolicy::UsbPower
+    }
+
+    pub fn power_on_at_alarm(&self) -> bool {
+        self.policy == PowerOnPolicy::Alarm
+    }
+
+    /// Follows the Zynq state so it can be restored after an SMC reset
+    pub fn tick(&mut self, status: Status) {
+        if self.policy != PowerOnPolicy::RestoreLast {
+            return;
+        }
+        // a fault isn't something to come back to
+        let power_on = match status {
+            Status::Running => true,
+            Status::Off | Status::Fault => false,
+            _ => return,
+        };
+        if power_on != self.last_power_on {
+            self.last_power_on = power_on;
+            settings::write(Slot::LastPowerOn, power_on as u32);
+        }
+    }
+}
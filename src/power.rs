@@ -5,6 +5,9 @@ use cortex_m::asm::{dsb, wfi};
 pub struct PowerState {
     power_state: bool,
     usb_connected: bool,
+    leds_active: bool,
+    button_active: bool,
+    power_up_waiting: bool,
     hseon: bool,
     pllon: bool,
     sw_bits: u8,
@@ -16,6 +19,9 @@ pub struct PowerState {
 static mut POWER_STATE: PowerState = PowerState {
     power_state: false,
     usb_connected: false,
+    leds_active: false,
+    button_active: false,
+    power_up_waiting: false,
     sw_bits: 0,
     hseon: false,
     pllon: false,
@@ -36,13 +42,41 @@ pub fn set_usb_connected(state: bool) {
     }
 }
 
+/// Keeps the SMC out of Stop mode while the LEDs are showing something
+pub fn set_leds_active(state: bool) {
+    unsafe {
+        POWER_STATE.leds_active = state;
+    }
+}
+
+/// Keeps the SMC out of Stop mode while a switch press is being classified
+pub fn set_button_active(state: bool) {
+    unsafe {
+        POWER_STATE.button_active = state;
+    }
+}
+
+/// Keeps the SMC out of Stop mode while a power-up waits on the battery
+/// and USB being read, which needs the tick
+pub fn set_power_up_waiting(state: bool) {
+    unsafe {
+        POWER_STATE.power_up_waiting = state;
+    }
+}
+
 pub fn init() {
     let rcc = unsafe { &*RCC::ptr() };
     rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
 }
 
 pub fn sleep_if_needed() -> bool {
-    if unsafe { POWER_STATE.power_state || POWER_STATE.usb_connected } {
+    if unsafe {
+        POWER_STATE.power_state
+            || POWER_STATE.usb_connected
+            || POWER_STATE.leds_active
+            || POWER_STATE.button_active
+            || POWER_STATE.power_up_waiting
+    } {
         return false;
     }
 
@@ -0,0 +1,117 @@
+use crate::hal::exti::{ConfigurableLine, Exti, ExtiLine, TriggerEdge};
+use crate::pac::{PWR, RCC, RTC};
+
+/// LSI is roughly 37 kHz, divided down to 1 Hz for the calendar.
+/// There's no LSE crystal, so the clock drifts along with LSI.
+const PREDIV_A: u32 = 127;
+const PREDIV_S: u32 = 288;
+
+pub const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// Date written when the calendar is first set up, Monday 1 January of
+/// year 01. Only the time of day is used, but INITS only reads back set
+/// once the year isn't zero, and that's how a running RTC is recognized.
+const START_DATE: u32 = (1 << 16) | (1 << 13) | (1 << 8) | 1;
+
+/// Starts the RTC from LSI, unless it's already running from before an
+/// SMC reset, and routes the alarm through EXTI so it can wake from Stop
+pub fn init(exti: &mut Exti) {
+    let rcc = unsafe { &*RCC::ptr() };
+    let pwr = unsafe { &*PWR::ptr() };
+    let rtc = unsafe { &*RTC::ptr() };
+
+    rcc.csr.modify(|_, w| w.lsion().set_bit());
+    while rcc.csr.read().lsirdy().bit_is_clear() {}
+
+    // the RTC clock bits live in the backup domain
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+    if rcc.csr.read().rtcen().bit_is_clear() {
+        rcc.csr
+            .modify(|_, w| unsafe { w.rtcsel().bits(0b10) }.rtcen().set_bit());
+    }
+
+    if rtc.isr.read().inits().bit_is_clear() {
+        unlock();
+        enter_init();
+        rtc.prer
+            .write(|w| unsafe { w.bits((PREDIV_A << 16) | PREDIV_S) });
+        rtc.tr.write(|w| unsafe { w.bits(0) });
+        rtc.dr.write(|w| unsafe { w.bits(START_DATE) });
+        exit_init();
+        lock();
+    }
+
+    exti.listen_configurable(ConfigurableLine::RtcAlarm, TriggerEdge::Rising);
+}
+
+/// Sets the time of day, in seconds since midnight
+pub fn set_time(seconds: u32) {
+    let rtc = unsafe { &*RTC::ptr() };
+    unlock();
+    enter_init();
+    rtc.tr.write(|w| unsafe { w.bits(to_bcd_time(seconds)) });
+    exit_init();
+    lock();
+}
+
+/// Sets a daily alarm at the given seconds since midnight, or turns it off
+pub fn set_alarm(seconds: Option<u32>) {
+    let rtc = unsafe { &*RTC::ptr() };
+    unlock();
+    rtc.cr
+        .modify(|_, w| w.alrae().clear_bit().alraie().clear_bit());
+    if let Some(seconds) = seconds {
+        while rtc.isr.read().alrawf().bit_is_clear() {}
+        // MSK4 ignores the date, so it fires every day
+        rtc.alrmar
+            .write(|w| unsafe { w.bits((1 << 31) | to_bcd_time(seconds)) });
+        rtc.cr.modify(|_, w| w.alrae().set_bit().alraie().set_bit());
+    }
+    lock();
+}
+
+/// Checks and clears the alarm flag, called from the RTC interrupt
+pub fn alarm_fired() -> bool {
+    let rtc = unsafe { &*RTC::ptr() };
+    Exti::unpend(ConfigurableLine::RtcAlarm);
+    if rtc.isr.read().alraf().bit_is_set() {
+        rtc.isr.modify(|_, w| w.alraf().clear_bit());
+        true
+    } else {
+        false
+    }
+}
+
+fn unlock() {
+    let rtc = unsafe { &*RTC::ptr() };
+    rtc.wpr.write(|w| unsafe { w.bits(0xca) });
+    rtc.wpr.write(|w| unsafe { w.bits(0x53) });
+}
+
+fn lock() {
+    let rtc = unsafe { &*RTC::ptr() };
+    rtc.wpr.write(|w| unsafe { w.bits(0xff) });
+}
+
+fn enter_init() {
+    let rtc = unsafe { &*RTC::ptr() };
+    rtc.isr.modify(|_, w| w.init().set_bit());
+    while rtc.isr.read().initf().bit_is_clear() {}
+}
+
+fn exit_init() {
+    let rtc = unsafe { &*RTC::ptr() };
+    rtc.isr.modify(|_, w| w.init().clear_bit());
+}
+
+/// Packs seconds since midnight into the TR/ALRMAR BCD layout
+fn to_bcd_time(seconds: u32) -> u32 {
+    let seconds = seconds % SECONDS_PER_DAY;
+    let (h, m, s) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    ((h / 10) << 20)
+        | ((h % 10) << 16)
+        | ((m / 10) << 12)
+        | ((m % 10) << 8)
+        | ((s / 10) << 4)
+        | (s % 10)
+}
@@ -0,0 +1,69 @@
+use crate::pac::FLASH;
+use crate::stc3115::DESIGN_CAPACITY_MAH;
+use core::ptr;
+
+/// Start of the STM32L073 data EEPROM
+const EEPROM_BASE: usize = 0x0808_0000;
+/// Marks the EEPROM as holding our layout, bump when the layout changes
+const MAGIC: u32 = 0x534d_4303;
+const PEKEY1: u32 = 0x89ab_cdef;
+const PEKEY2: u32 = 0x0203_0405;
+
+/// Word slots in data EEPROM for settings that survive an SMC reset
+#[derive(Clone, Copy)]
+pub enum Slot {
+    Magic = 0,
+    PowerOnPolicy = 1,
+    LastPowerOn = 2,
+    AlarmTime = 3,
+    LowBatteryWarn = 4,
+    LowBatteryCritical = 5,
+    /// Lifetime discharge in tenths of a percent
+    BatteryDischarged = 6,
+    /// Learned full charge capacity in mAh
+    BatteryCapacity = 7,
+}
+
+/// Every slot but the magic, with the value it gets on a blank EEPROM
+const DEFAULTS: [(Slot, u32); 7] = [
+    (Slot::PowerOnPolicy, 0),
+    (Slot::LastPowerOn, 0),
+    (Slot::AlarmTime, 0),
+    (Slot::LowBatteryWarn, 10),
+    (Slot::LowBatteryCritical, 3),
+    (Slot::BatteryDischarged, 0),
+    (Slot::BatteryCapacity, DESIGN_CAPACITY_MAH),
+];
+
+/// Sets up defaults if the EEPROM is blank or from an older layout
+pub fn init() {
+    if read(Slot::Magic) != MAGIC {
+        for &(slot, value) in DEFAULTS.iter() {
+            write(slot, value);
+        }
+        write(Slot::Magic, MAGIC);
+    }
+}
+
+pub fn read(slot: Slot) -> u32 {
+    unsafe { ptr::read_volatile(slot_address(slot) as *const u32) }
+}
+
+/// Writes a slot, skipping the write if it already holds `value`
+pub fn write(slot: Slot, value: u32) {
+    if read(slot) == value {
+        return;
+    }
+    let flash = unsafe { &*FLASH::ptr() };
+    if flash.pecr.read().pelock().bit_is_set() {
+        flash.pekeyr.write(|w| unsafe { w.bits(PEKEY1) });
+        flash.pekeyr.write(|w| unsafe { w.bits(PEKEY2) });
+    }
+    unsafe { ptr::write_volatile(slot_address(slot) as *mut u32, value) };
+    while flash.sr.read().bsy().bit_is_set() {}
+    flash.pecr.modify(|_, w| w.pelock().set_bit());
+}
+
+fn slot_address(slot: Slot) -> usize {
+    EEPROM_BASE + slot as usize * 4
+}
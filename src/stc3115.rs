@@ -0,0 +1,205 @@
+//! STC3115 fuel gauge, run in mixed mode so the coulomb counter and the
+//! voltage based estimate correct each other.
+
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+pub const ADDR: u8 = 0x70;
+const CHIP_ID: u8 = 0x14;
+
+const REG_MODE: u8 = 0;
+const REG_CTRL: u8 = 1;
+const REG_OCV: u8 = 13;
+const REG_CC_CNF: u8 = 15;
+const REG_VM_CNF: u8 = 17;
+const REG_ALARM_SOC: u8 = 19;
+const REG_ALARM_VOLTAGE: u8 = 20;
+const REG_ID: u8 = 24;
+
+const MODE_ALM_ENA: u8 = 1 << 3;
+const MODE_GG_RUN: u8 = 1 << 4;
+const CTRL_IO0DATA: u8 = 1 << 0;
+const CTRL_GG_RST: u8 = 1 << 1;
+const CTRL_BATFAIL: u8 = 1 << 3;
+const CTRL_PORDET: u8 = 1 << 4;
+const CTRL_ALM_SOC: u8 = 1 << 5;
+const CTRL_ALM_VOLT: u8 = 1 << 6;
+
+/// Sense resistor in mΩ
+const SENSE_MOHM: u32 = 10;
+/// Nominal capacity of the pack in mAh
+pub const DESIGN_CAPACITY_MAH: u32 = 2000;
+/// Internal resistance of the pack in mΩ
+const RINT_MOHM: u32 = 200;
+/// Alarm below this state of charge, in percent
+const ALARM_SOC_PERCENT: u32 = 5;
+/// Alarm below this battery voltage, in mV
+const ALARM_VOLTAGE_MV: u32 = 3300;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    /// Something other than an STC3115 answered at its address
+    WrongId(u8),
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(error: E) -> Self {
+        Error::I2c(error)
+    }
+}
+
+/// One round of measurements from the gauge
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Reading {
+    /// State of charge in tenths of a percent
+    pub soc: u16,
+    pub voltage_mv: u16,
+    /// Battery current in mA, positive while charging
+    pub current_ma: i16,
+    pub temperature_c: i8,
+    pub soc_alarm: bool,
+    pub voltage_alarm: bool,
+}
+
+pub struct Stc3115 {
+    running: bool,
+    reading: Option<Reading>,
+    battery_failed: bool,
+}
+
+impl Stc3115 {
+    pub const fn new() -> Self {
+        Self {
+            running: false,
+            reading: None,
+            battery_failed: false,
+        }
+    }
+
+    /// The last successful reading, if there's been one
+    pub fn reading(&self) -> Option<&Reading> {
+        self.reading.as_ref()
+    }
+
+    /// Configures the gauge for the pack and starts it.
+    ///
+    /// Writing the OCV back restarts the estimate from the current open
+    /// circuit voltage, which is what the datasheet asks for after a
+    /// battery swap or a gauge reset.
+    fn start<I, E>(&mut self, i2c: &mut I) -> Result<(), Error<E>>
+    where
+        I: Write<Error = E> + WriteRead<Error = E>,
+    {
+        let mut id = [0; 1];
+        i2c.write_read(ADDR, &[REG_ID], &mut id)?;
+        if id[0] != CHIP_ID {
+            return Err(Error::WrongId(id[0]));
+        }
+        let mut ocv = [0; 2];
+        i2c.write_read(ADDR, &[REG_OCV], &mut ocv)?;
+
+        i2c.write(ADDR, &[REG_MODE, 0])?;
+        write_u16(i2c, REG_CC_CNF, cc_cnf())?;
+        write_u16(i2c, REG_VM_CNF, vm_cnf())?;
+        i2c.write(ADDR, &[REG_ALARM_SOC, (ALARM_SOC_PERCENT * 2) as u8])?;
+        i2c.write(
+            ADDR,
+            &[REG_ALARM_VOLTAGE, (ALARM_VOLTAGE_MV * 10 / 176) as u8],
+        )?;
+        i2c.write(ADDR, &[REG_OCV, ocv[0], ocv[1]])?;
+        i2c.write(ADDR, &[REG_CTRL, CTRL_IO0DATA | CTRL_GG_RST])?;
+        i2c.write(ADDR, &[REG_MODE, MODE_ALM_ENA | MODE_GG_RUN])?;
+        self.running = true;
+        Ok(())
+    }
+
+    /// Started, but without a first conversion to read yet
+    pub fn is_starting(&self) -> bool {
+        self.running && self.reading.is_none()
+    }
+
+    /// The gauge saw the battery voltage collapse, or the battery removed,
+    /// and hasn't had a good reading since
+    pub fn battery_failed(&self) -> bool {
+        self.battery_failed
+    }
+
+    /// Puts the gauge in standby, its lowest power mode. It stops counting
+    /// there, so the next update starts it over from the OCV.
+    pub fn standby<I, E>(&mut self, i2c: &mut I) -> Result<(), E>
+    where
+        I: Write<Error = E>,
+    {
+        self.running = false;
+        self.reading = None;
+        i2c.write(ADDR, &[REG_MODE, 0])
+    }
+
+    /// Reads the latest measurements, starting the gauge first if it isn't
+    /// running yet or lost its state
+    pub fn update<I, E>(&mut self, i2c: &mut I) -> Result<(), Error<E>>
+    where
+        I: Write<Error = E> + WriteRead<Error = E>,
+    {
+        if !self.running {
+            // the first conversion isn't ready yet, read next time
+            return self.start(i2c);
+        }
+
+        // mode, ctrl, soc, counter, current, voltage, temperature
+        let mut regs = [0; 11];
+        i2c.write_read(ADDR, &[REG_MODE], &mut regs)?;
+        let ctrl = regs[1];
+        if ctrl & (CTRL_BATFAIL | CTRL_PORDET) != 0 {
+            // the gauge reset or the battery was swapped, start over
+            self.battery_failed = ctrl & CTRL_BATFAIL != 0;
+            self.running = false;
+            self.reading = None;
+            return Ok(());
+        }
+        let soc = u16::from_le_bytes([regs[2], regs[3]]);
+        let current = sign_extend(u16::from_le_bytes([regs[6], regs[7]]), 14);
+        let voltage = sign_extend(u16::from_le_bytes([regs[8], regs[9]]), 12);
+        self.battery_failed = false;
+        self.reading = Some(Reading {
+            soc: (soc as u32 * 10 / 512) as u16,
+            // 2.2 mV per LSB
+            voltage_mv: (voltage.max(0) as u32 * 22 / 10) as u16,
+            // 5.88 uV across the sense resistor per LSB
+            current_ma: (current as i32 * 588 / (100 * SENSE_MOHM as i32)) as i16,
+            temperature_c: regs[10] as i8,
+            soc_alarm: ctrl & CTRL_ALM_SOC != 0,
+            voltage_alarm: ctrl & CTRL_ALM_VOLT != 0,
+        });
+
+        if ctrl & (CTRL_ALM_SOC | CTRL_ALM_VOLT) != 0 {
+            // clearing re-arms them, they come right back if still true
+            i2c.write(ADDR, &[REG_CTRL, CTRL_IO0DATA])?;
+        }
+        Ok(())
+    }
+}
+
+/// Coulomb counter gain, from the sense resistor and capacity
+fn cc_cnf() -> u16 {
+    ((SENSE_MOHM * DESIGN_CAPACITY_MAH * 250 + 6194) / 12389) as u16
+}
+
+/// Voltage mode gain, from the internal resistance and capacity
+fn vm_cnf() -> u16 {
+    ((RINT_MOHM * DESIGN_CAPACITY_MAH * 50 + 24444) / 48889) as u16
+}
+
+fn write_u16<I, E>(i2c: &mut I, reg: u8, value: u16) -> Result<(), E>
+where
+    I: Write<Error = E>,
+{
+    let bytes = value.to_le_bytes();
+    i2c.write(ADDR, &[reg, bytes[0], bytes[1]])
+}
+
+/// Sign extends the low `bits` of a register
+fn sign_extend(value: u16, bits: u32) -> i16 {
+    let shift = 16 - bits;
+    ((value << shift) as i16) >> shift
+}
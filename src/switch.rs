@@ -1,28 +1,189 @@
+use super::power;
 use stm32l0xx_hal::{
     exti::{Exti, ExtiLine, GpioLine, TriggerEdge},
     gpio::{gpiob::PB0, Floating, Input, Port},
+    prelude::*,
     syscfg::SYSCFG,
 };
 
-pub struct SwitchState {}
+/// Ticks the switch has to sit still after an edge before we believe it.
+/// An edge can land just before a tick, so it takes 2 to be sure of at
+/// least a full 100 ms.
+const DEBOUNCE_TICKS: u32 = 2;
+/// Ticks after a short press to wait for a second one
+const DOUBLE_PRESS_TICKS: u32 = 4;
+/// Ticks held before a press counts as long
+const LONG_PRESS_TICKS: u32 = 20;
+/// Ticks held before a press counts as very long
+const VERY_LONG_PRESS_TICKS: u32 = 60;
+
+pub struct SwitchState {
+    pin: PB0<Input<Floating>>,
+    last_edge: Option<u32>,
+    pressed: bool,
+    button_state: ButtonState,
+    forward: ButtonMask,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ButtonEvent {
+    ShortPress,
+    DoublePress,
+    /// Still held, fires once the long press time is reached
+    LongPress,
+    /// Still held, fires once the very long press time is reached
+    VeryLongPress,
+}
+
+impl ButtonEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            ButtonEvent::ShortPress => "short",
+            ButtonEvent::DoublePress => "double",
+            ButtonEvent::LongPress => "long",
+            ButtonEvent::VeryLongPress => "very-long",
+        }
+    }
+}
+
+/// Set of presses passed on to the Zynq instead of being handled here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ButtonMask(u8);
+
+impl ButtonMask {
+    pub const NONE: Self = ButtonMask(0);
+
+    /// Adds `event`, except a very long press, which always stays with the
+    /// SMC so a hung OS can still be switched off
+    pub fn with(self, event: ButtonEvent) -> Self {
+        match event {
+            ButtonEvent::VeryLongPress => self,
+            _ => ButtonMask(self.0 | 1 << event as u8),
+        }
+    }
+
+    pub fn contains(self, event: ButtonEvent) -> bool {
+        self.0 & 1 << event as u8 != 0
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ButtonState {
+    Idle,
+    Pressed { since: u32, second: bool },
+    WaitingForSecond { since: u32 },
+    HeldLong { since: u32 },
+    HeldVeryLong,
+}
 
 impl SwitchState {
-    pub fn new(_pb0: PB0<Input<Floating>>, exti: &mut Exti, syscfg: &mut SYSCFG) -> Self {
+    pub fn new(pb0: PB0<Input<Floating>>, exti: &mut Exti, syscfg: &mut SYSCFG) -> Self {
         exti.listen_gpio(
             syscfg,
             Port::PB,
             GpioLine::from_raw_line(0).unwrap(),
-            TriggerEdge::Falling,
+            TriggerEdge::Both,
         );
-        Self {}
+        Self {
+            pin: pb0,
+            last_edge: None,
+            pressed: false,
+            button_state: ButtonState::Idle,
+            forward: ButtonMask::NONE,
+        }
+    }
+
+    /// Whether `event` goes to the Zynq while it's running
+    pub fn forwards(&self, event: ButtonEvent) -> bool {
+        self.forward.contains(event)
+    }
+
+    pub fn set_forward(&mut self, forward: ButtonMask) {
+        self.forward = forward;
     }
 
-    pub fn was_toggled(&mut self) -> bool {
+    /// Notes the edge for debouncing, the tick does the rest
+    pub fn handle_interrupt(&mut self, tick: u32) {
         if Exti::is_pending(GpioLine::from_raw_line(0).unwrap()) {
             Exti::unpend(GpioLine::from_raw_line(0).unwrap());
-            true
-        } else {
-            false
+            self.last_edge = Some(tick);
+            // stay awake until the press is classified
+            power::set_button_active(true);
+        }
+    }
+
+    pub fn tick(&mut self, tick: u32) -> Option<ButtonEvent> {
+        if let Some(at) = self.last_edge {
+            if tick.wrapping_sub(at) >= DEBOUNCE_TICKS {
+                self.last_edge = None;
+                // the switch pulls the line low while pressed
+                self.pressed = self.pin.is_low().unwrap();
+            }
+        }
+
+        let (button_state, event) = match self.button_state {
+            ButtonState::Idle => {
+                if self.pressed {
+                    let pressed = ButtonState::Pressed {
+                        since: tick,
+                        second: false,
+                    };
+                    (pressed, None)
+                } else {
+                    (ButtonState::Idle, None)
+                }
+            }
+            ButtonState::Pressed { since, second } => {
+                if !self.pressed {
+                    if second {
+                        (ButtonState::Idle, Some(ButtonEvent::DoublePress))
+                    } else {
+                        (ButtonState::WaitingForSecond { since: tick }, None)
+                    }
+                } else if tick.wrapping_sub(since) >= LONG_PRESS_TICKS {
+                    (
+                        ButtonState::HeldLong { since },
+                        Some(ButtonEvent::LongPress),
+                    )
+                } else {
+                    (self.button_state, None)
+                }
+            }
+            ButtonState::WaitingForSecond { since } => {
+                if self.pressed {
+                    let pressed = ButtonState::Pressed {
+                        since: tick,
+                        second: true,
+                    };
+                    (pressed, None)
+                } else if tick.wrapping_sub(since) >= DOUBLE_PRESS_TICKS {
+                    (ButtonState::Idle, Some(ButtonEvent::ShortPress))
+                } else {
+                    (self.button_state, None)
+                }
+            }
+            ButtonState::HeldLong { since } => {
+                if !self.pressed {
+                    (ButtonState::Idle, None)
+                } else if tick.wrapping_sub(since) >= VERY_LONG_PRESS_TICKS {
+                    (ButtonState::HeldVeryLong, Some(ButtonEvent::VeryLongPress))
+                } else {
+                    (self.button_state, None)
+                }
+            }
+            ButtonState::HeldVeryLong => {
+                if !self.pressed {
+                    (ButtonState::Idle, None)
+                } else {
+                    (ButtonState::HeldVeryLong, None)
+                }
+            }
+        };
+        self.button_state = button_state;
+
+        if self.button_state == ButtonState::Idle && self.last_edge.is_none() {
+            power::set_button_active(false);
         }
+        event
     }
 }
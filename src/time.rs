@@ -0,0 +1,21 @@
+use cortex_m::peripheral::{SCB, SYST};
+
+/// Length of one SysTick tick
+pub const TICK_US: u32 = 100_000;
+
+/// Microseconds since boot, with sub-tick resolution read from SysTick.
+///
+/// `tick` is the tick count as seen by the caller. Wraps after about 71
+/// minutes, so only differences are meaningful.
+pub fn micros(tick: u32) -> u32 {
+    let reload = SYST::get_reload();
+    let mut tick = tick;
+    let mut current = SYST::get_current();
+    // the counter already wrapped, but the tick task hasn't run yet
+    if SCB::is_pendst_pending() {
+        tick = tick.wrapping_add(1);
+        current = SYST::get_current();
+    }
+    let elapsed = (reload - current) as u64 * TICK_US as u64 / (reload as u64 + 1);
+    tick.wrapping_mul(TICK_US).wrapping_add(elapsed as u32)
+}
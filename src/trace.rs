@@ -0,0 +1,164 @@
+use crate::zynq::{Direction, RAIL_COUNT};
+
+/// Number of power-up/power-down traces kept
+pub const TRACE_COUNT: usize = 4;
+const MAX_EVENTS: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    Enable(usize),
+    Disable(usize),
+    PowerGoodRise(usize),
+    PowerGoodFall(usize),
+    PorRelease,
+    PorAssert,
+    Fault,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Event {
+    pub at_us: u32,
+    pub kind: EventKind,
+}
+
+/// Timestamped steps of one power-up or power-down
+#[derive(Clone, Copy)]
+pub struct Trace {
+    pub direction: Direction,
+    pub start_us: u32,
+    events: [Event; MAX_EVENTS],
+    len: usize,
+}
+
+impl Trace {
+    const fn empty() -> Self {
+        Self {
+            direction: Direction::Up,
+            start_us: 0,
+            events: [Event {
+                at_us: 0,
+                kind: EventKind::Fault,
+            }; MAX_EVENTS],
+            len: 0,
+        }
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events[..self.len]
+    }
+}
+
+/// Running min/avg/max of a rail's ramp time
+#[derive(Clone, Copy)]
+pub struct RampStats {
+    pub min_us: u32,
+    pub max_us: u32,
+    pub count: u32,
+    total_us: u64,
+}
+
+impl RampStats {
+    const fn new() -> Self {
+        Self {
+            min_us: u32::max_value(),
+            max_us: 0,
+            count: 0,
+            total_us: 0,
+        }
+    }
+
+    fn add(&mut self, ramp_us: u32) {
+        self.min_us = self.min_us.min(ramp_us);
+        self.max_us = self.max_us.max(ramp_us);
+        self.count += 1;
+        self.total_us += ramp_us as u64;
+    }
+
+    pub fn avg_us(&self) -> u32 {
+        if self.count == 0 {
+            0
+        } else {
+            (self.total_us / self.count as u64) as u32
+        }
+    }
+}
+
+/// Keeps the last few sequencing traces, and ramp statistics per rail
+pub struct PowerLog {
+    traces: [Trace; TRACE_COUNT],
+    next: usize,
+    count: usize,
+    recording: bool,
+    switched_at: [Option<u32>; RAIL_COUNT],
+    pub rise: [RampStats; RAIL_COUNT],
+    pub fall: [RampStats; RAIL_COUNT],
+}
+
+impl PowerLog {
+    pub const fn new() -> Self {
+        Self {
+            traces: [Trace::empty(); TRACE_COUNT],
+            next: 0,
+            count: 0,
+            recording: false,
+            switched_at: [None; RAIL_COUNT],
+            rise: [RampStats::new(); RAIL_COUNT],
+            fall: [RampStats::new(); RAIL_COUNT],
+        }
+    }
+
+    /// Starts a new trace, replacing the oldest one
+    pub fn begin(&mut self, direction: Direction, now_us: u32) {
+        self.traces[self.next] = Trace {
+            direction,
+            start_us: now_us,
+            ..Trace::empty()
+        };
+        self.next = (self.next + 1) % TRACE_COUNT;
+        self.count = (self.count + 1).min(TRACE_COUNT);
+        self.recording = true;
+        self.switched_at = [None; RAIL_COUNT];
+    }
+
+    /// Stops adding to the current trace, e.g. once the sequence finished
+    pub fn end(&mut self) {
+        self.recording = false;
+    }
+
+    pub fn record(&mut self, now_us: u32, kind: EventKind) {
+        match kind {
+            EventKind::Enable(rail) | EventKind::Disable(rail) => {
+                self.switched_at[rail] = Some(now_us)
+            }
+            // only edges that follow our own enable or disable are ramps
+            EventKind::PowerGoodRise(rail) => {
+                if let Some(at) = self.switched_at[rail].take() {
+                    self.rise[rail].add(now_us.wrapping_sub(at));
+                }
+            }
+            EventKind::PowerGoodFall(rail) => {
+                if let Some(at) = self.switched_at[rail].take() {
+                    self.fall[rail].add(now_us.wrapping_sub(at));
+                }
+            }
+            _ => {}
+        }
+        if !self.recording {
+            return;
+        }
+        let trace = &mut self.traces[(self.next + TRACE_COUNT - 1) % TRACE_COUNT];
+        if trace.len < MAX_EVENTS {
+            trace.events[trace.len] = Event {
+                at_us: now_us,
+                kind,
+            };
+            trace.len += 1;
+        }
+    }
+
+    /// Traces from oldest to newest
+    pub fn traces(&self) -> impl Iterator<Item = &Trace> {
+        let first = (self.next + TRACE_COUNT - self.count) % TRACE_COUNT;
+        (0..self.count).map(move |i| &self.traces[(first + i) % TRACE_COUNT])
+    }
+}
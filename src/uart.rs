@@ -9,6 +9,7 @@ use crate::hal::{
     serial,
     serial::{Event, Serial1LpExt},
 };
+use crate::link::{CommandQueue, LineFilter, Message, Request, Source, MAX_MESSAGE_LEN};
 use crate::pac::{
     dma1::ch::cr::{DIR_A, PL_A},
     DMA1, LPUART1,
@@ -25,6 +26,9 @@ pub struct UartState {
     tx_producer: bbqueue::Producer<'static, U256>,
     tx_consumer: bbqueue::Consumer<'static, U256>,
     tx_cur_read_len: usize,
+    host_filter: LineFilter,
+    zynq_filter: LineFilter,
+    commands: CommandQueue,
 }
 
 const UART_BAUD: u32 = 115200;
@@ -35,6 +39,22 @@ static mut IN_BUFFER: [u8; IN_BUFFER_SIZE] = [0; IN_BUFFER_SIZE];
 
 static TX_BUFFER: BBBuffer<U256> = BBBuffer(ConstBBBuffer::new());
 
+const USB_READ_SIZE: usize = 64;
+
+/// Copies `data` into the TX queue, dropping whatever doesn't fit
+fn enqueue_tx(producer: &mut bbqueue::Producer<'static, U256>, mut data: &[u8]) {
+    while !data.is_empty() {
+        let mut grant = match producer.grant_max_remaining(data.len()) {
+            Ok(g) => g,
+            Err(_) => return,
+        };
+        let len = grant.buf().len();
+        grant.buf().copy_from_slice(&data[..len]);
+        grant.commit(len);
+        data = &data[len..];
+    }
+}
+
 impl UartState {
     pub fn new(
         lpuart1: LPUART1,
@@ -92,6 +112,9 @@ impl UartState {
             tx_producer,
             tx_consumer,
             tx_cur_read_len: 0,
+            host_filter: LineFilter::new(),
+            zynq_filter: LineFilter::new(),
+            commands: CommandQueue::new(),
         }
     }
 
@@ -99,11 +122,11 @@ impl UartState {
         let rx_channel = &mut self.dma.channels.channel3;
         if rx_channel.is_complete() {
             rx_channel.clear_complete_flag();
-            usb.write_uart_data(unsafe { &IN_BUFFER[self.last_flush..IN_BUFFER_SIZE] });
+            self.forward_rx(usb, self.last_flush, IN_BUFFER_SIZE);
             self.last_flush = 0;
         } else if rx_channel.is_half_complete() {
             rx_channel.clear_half_complete_flag();
-            usb.write_uart_data(unsafe { &IN_BUFFER[self.last_flush..HALF_IN_BUFFER_SIZE] });
+            self.forward_rx(usb, self.last_flush, HALF_IN_BUFFER_SIZE);
             self.last_flush = HALF_IN_BUFFER_SIZE;
         }
         let tx_channel = &mut self.dma.channels.channel2;
@@ -126,7 +149,7 @@ impl UartState {
 
             let transfers_left = rx_channel.get_transfers_left(&mut self.dma.handle);
             let pos = IN_BUFFER_SIZE - transfers_left as usize;
-            usb.write_uart_data(unsafe { &IN_BUFFER[self.last_flush..pos] });
+            self.forward_rx(usb, self.last_flush, pos);
             self.last_flush = pos;
         }
         if self.tx.is_transmission_complete() {
@@ -137,12 +160,49 @@ impl UartState {
     }
 
     pub fn interrupt_usb(&mut self, usb: &mut UsbState) {
-        let mut grant = self.tx_producer.grant_max_remaining(128).unwrap();
-        let num_read = usb.read_usb_data(grant.buf());
-        grant.commit(num_read);
-        if num_read > 0 {
-            self.start_tx();
+        let mut buffer = [0; USB_READ_SIZE];
+        let num_read = usb.read_usb_data(&mut buffer);
+        if num_read == 0 {
+            return;
         }
+        let Self {
+            host_filter,
+            tx_producer,
+            commands,
+            ..
+        } = self;
+        host_filter.process(
+            &buffer[..num_read],
+            |data| enqueue_tx(tx_producer, data),
+            |line| commands.push_line(Source::Host, line),
+        );
+        self.start_tx();
+    }
+
+    /// Queues a message line to the Zynq
+    pub fn send(&mut self, message: Message) {
+        let mut buffer = [0; MAX_MESSAGE_LEN];
+        let len = message.write(&mut buffer);
+        enqueue_tx(&mut self.tx_producer, &buffer[..len]);
+        self.start_tx();
+    }
+
+    /// Next command from the host or the Zynq, if any arrived
+    pub fn take_command(&mut self) -> Option<Request> {
+        self.commands.pop()
+    }
+
+    fn forward_rx(&mut self, usb: &mut UsbState, start: usize, end: usize) {
+        let Self {
+            zynq_filter,
+            commands,
+            ..
+        } = self;
+        zynq_filter.process(
+            unsafe { &IN_BUFFER[start..end] },
+            |data| usb.write_uart_data(data),
+            |line| commands.push_line(Source::Zynq, line),
+        );
     }
 
     pub fn start_tx(&mut self) {
@@ -1,4 +1,6 @@
 use super::power;
+use crate::bc12::{Budget, ChargerDetect};
+use crate::bq24250::InputLimit;
 use crate::hal::{
     exti::{Exti, ExtiLine, GpioLine, TriggerEdge},
     gpio::{
@@ -10,16 +12,29 @@ use crate::hal::{
     usb::{UsbBus, USB},
 };
 use crate::pac;
+use bbqueue::{consts::U2048, BBBuffer, ConstBBBuffer};
+use core::fmt;
 use embedded_hal::digital::v2::InputPin;
 use usb_device::{bus::UsbBusAllocator, prelude::*};
 use usbd_serial::{SerialPort, USB_CLASS_CDC};
 
 static mut USB_BUS: Option<UsbBusAllocator<UsbBus<USB>>> = None;
 
+/// SMC replies to the host, drained into the serial port as it has room.
+/// Sized for the largest dump, a full `@smc trace` at about 1.7 KB, since
+/// replies are written with the USB interrupt locked out.
+static HOST_BUFFER: BBBuffer<U2048> = BBBuffer(ConstBBBuffer::new());
+const TRUNCATED: &[u8] = b"\n@smc truncated\n";
+
 pub struct UsbState {
     device: UsbDevice<'static, UsbBus<USB>>,
     serial: SerialPort<'static, UsbBus<USB>>,
     usb_detect: PA10<Input<Floating>>,
+    charger_detect: ChargerDetect,
+    host_producer: bbqueue::Producer<'static, U2048>,
+    host_consumer: bbqueue::Consumer<'static, U2048>,
+    /// Host output was dropped, and the host hasn't been told yet
+    truncated: bool,
 }
 
 impl UsbState {
@@ -55,12 +70,23 @@ impl UsbState {
         .max_power(500)
         .build();
 
-        power::set_usb_connected(pa10.is_high().unwrap());
+        let (host_producer, host_consumer) = HOST_BUFFER.try_split().unwrap();
+
+        let connected = pa10.is_high().unwrap();
+        power::set_usb_connected(connected);
         device.bus().force_reenumeration(|| {});
+        let mut charger_detect = ChargerDetect::new();
+        if connected {
+            charger_detect.start();
+        }
         UsbState {
             device,
             serial,
             usb_detect: pa10,
+            charger_detect,
+            host_producer,
+            host_consumer,
+            truncated: false,
         }
     }
 
@@ -70,6 +96,51 @@ impl UsbState {
 
     pub fn poll(&mut self) {
         self.device.poll(&mut [&mut self.serial]);
+        self.flush_host_data();
+    }
+
+    /// Queues SMC output for the host. Whatever doesn't fit is dropped,
+    /// and the host gets a `@smc truncated` line once there's room.
+    /// Returns whether all of it fit.
+    pub fn write_host_data(&mut self, data: &[u8]) -> bool {
+        let fit = !self.truncated && self.queue_host_data(data);
+        self.truncated |= !fit;
+        self.flush_host_data();
+        fit
+    }
+
+    fn queue_host_data(&mut self, mut data: &[u8]) -> bool {
+        while !data.is_empty() {
+            let mut grant = match self.host_producer.grant_max_remaining(data.len()) {
+                Ok(g) => g,
+                Err(_) => return false,
+            };
+            let len = grant.buf().len();
+            grant.buf().copy_from_slice(&data[..len]);
+            grant.commit(len);
+            data = &data[len..];
+        }
+        true
+    }
+
+    fn flush_host_data(&mut self) {
+        if self.truncated {
+            // only once the marker fits whole
+            if let Ok(mut grant) = self.host_producer.grant_exact(TRUNCATED.len()) {
+                grant.buf().copy_from_slice(TRUNCATED);
+                grant.commit(TRUNCATED.len());
+                self.truncated = false;
+            }
+        }
+        let grant = match self.host_consumer.read() {
+            Ok(g) => g,
+            Err(_) => return,
+        };
+        let written = match self.serial.write(grant.buf()) {
+            Ok(c) => c,
+            Err(_) => 0,
+        };
+        grant.release(written);
     }
 
     pub fn write_uart_data(&mut self, data: &[u8]) {
@@ -86,10 +157,50 @@ impl UsbState {
         }
     }
 
-    pub fn handle_detect_interrupt(&mut self) {
+    /// Steps BC1.2 detection along, called every tick
+    pub fn tick(&mut self) {
+        self.charger_detect.tick();
+    }
+
+    /// Whether the port type is still being detected
+    pub fn is_detecting(&self) -> bool {
+        self.charger_detect.is_running()
+    }
+
+    /// What the charger may draw, going by the port type and whether the
+    /// host configured or suspended us. Until detection is done it's the
+    /// unconfigured budget, which every port can supply.
+    pub fn input_budget(&self) -> Budget {
+        match self.charger_detect.port() {
+            Some(port) => port.budget(self.device.state()),
+            None => Budget::Limit(InputLimit::Usb100mA),
+        }
+    }
+
+    /// Returns true when USB power was just plugged in
+    pub fn handle_detect_interrupt(&mut self) -> bool {
         if Exti::is_pending(GpioLine::from_raw_line(10).unwrap()) {
             Exti::unpend(GpioLine::from_raw_line(10).unwrap());
-            power::set_usb_connected(self.usb_detect.is_high().unwrap());
+            let connected = self.usb_detect.is_high().unwrap();
+            power::set_usb_connected(connected);
+            if connected {
+                self.charger_detect.start();
+            } else {
+                self.charger_detect.stop();
+            }
+            connected
+        } else {
+            false
+        }
+    }
+}
+
+impl fmt::Write for UsbState {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.write_host_data(s.as_bytes()) {
+            Ok(())
+        } else {
+            Err(fmt::Error)
         }
     }
 }
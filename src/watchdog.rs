@@ -0,0 +1,150 @@
+use crate::zynq::Status;
+
+/// Heartbeat watchdog for the OS on the Zynq.
+///
+/// Once the Zynq is running it has to send a heartbeat within the boot
+/// timeout, and then keep sending them within the runtime timeout. When it
+/// doesn't, the Zynq gets reset or power-cycled, up to a bounded number of
+/// times before the watchdog gives up.
+pub struct Watchdog {
+    recovery: Recovery,
+    state: WatchdogState,
+    retries: u8,
+}
+
+/// How the watchdog tries to bring a hung Zynq back
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Recovery {
+    Reset,
+    PowerCycle,
+}
+
+/// What the watchdog needs done to the Zynq
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Reset,
+    PowerDown,
+    PowerUp,
+    GiveUp,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum WatchdogState {
+    Disabled,
+    Idle,
+    Booting { since: u32 },
+    Running { last_heartbeat: u32 },
+    Cycling,
+    GaveUp,
+}
+
+/// Ticks the OS gets from power-on to its first heartbeat
+const BOOT_TIMEOUT_TICKS: u32 = 600;
+/// Ticks allowed between heartbeats once the OS is up
+const RUNTIME_TIMEOUT_TICKS: u32 = 100;
+/// Recoveries in a row without a heartbeat before giving up
+const MAX_RETRIES: u8 = 3;
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Self {
+            recovery: Recovery::Reset,
+            state: WatchdogState::Disabled,
+            retries: 0,
+        }
+    }
+
+    pub fn enable(&mut self, recovery: Recovery) {
+        self.recovery = recovery;
+        if self.state == WatchdogState::Disabled {
+            self.state = WatchdogState::Idle;
+        }
+    }
+
+    pub fn disable(&mut self) {
+        self.state = WatchdogState::Disabled;
+        self.retries = 0;
+    }
+
+    /// Starts over after giving up, e.g. when someone powers on by hand
+    pub fn rearm(&mut self) {
+        self.retries = 0;
+        if self.state == WatchdogState::GaveUp {
+            self.state = WatchdogState::Idle;
+        }
+    }
+
+    pub fn gave_up(&self) -> bool {
+        self.state == WatchdogState::GaveUp
+    }
+
+    pub fn heartbeat(&mut self, tick: u32) {
+        match self.state {
+            WatchdogState::Booting { .. } | WatchdogState::Running { .. } => {
+                self.state = WatchdogState::Running {
+                    last_heartbeat: tick,
+                };
+                self.retries = 0;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn tick(&mut self, tick: u32, status: Status) -> Option<Action> {
+        match self.state {
+            WatchdogState::Disabled | WatchdogState::GaveUp => None,
+            WatchdogState::Cycling => match status {
+                Status::Off => {
+                    self.state = WatchdogState::Idle;
+                    Some(Action::PowerUp)
+                }
+                // the sequencer has bigger problems, leave it be
+                Status::Fault => {
+                    self.state = WatchdogState::Idle;
+                    None
+                }
+                _ => None,
+            },
+            _ if status != Status::Running => {
+                self.state = WatchdogState::Idle;
+                None
+            }
+            WatchdogState::Idle => {
+                self.state = WatchdogState::Booting { since: tick };
+                None
+            }
+            WatchdogState::Booting { since } => {
+                if tick.wrapping_sub(since) >= BOOT_TIMEOUT_TICKS {
+                    Some(self.recover())
+                } else {
+                    None
+                }
+            }
+            WatchdogState::Running { last_heartbeat } => {
+                if tick.wrapping_sub(last_heartbeat) >= RUNTIME_TIMEOUT_TICKS {
+                    Some(self.recover())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn recover(&mut self) -> Action {
+        if self.retries >= MAX_RETRIES {
+            self.state = WatchdogState::GaveUp;
+            return Action::GiveUp;
+        }
+        self.retries += 1;
+        match self.recovery {
+            Recovery::Reset => {
+                self.state = WatchdogState::Idle;
+                Action::Reset
+            }
+            Recovery::PowerCycle => {
+                self.state = WatchdogState::Cycling;
+                Action::PowerDown
+            }
+        }
+    }
+}
@@ -1,43 +1,135 @@
 use super::power;
+use crate::time;
+use crate::trace::{EventKind, PowerLog};
+use core::fmt::{self, Write};
 use stm32l0xx_hal::{
+    exti::{Exti, ExtiLine, GpioLine, TriggerEdge},
     gpio::{
-        gpioc::{PC0, PC1, PC2, PC3, PC4, PC5, PC6, PC7, PC8},
-        Floating, Input, Output, PushPull,
+        gpioc::{PC, PC0, PC1, PC2, PC3, PC4, PC5, PC6, PC7, PC8},
+        Floating, Input, Output, Port, PushPull,
     },
     prelude::*,
+    syscfg::SYSCFG,
 };
 
 pub struct ZynqState {
-    power_supplies: PowerSupplies,
+    rails: [Rail; RAIL_COUNT],
+    rail_states: [RailState; RAIL_COUNT],
+    zynq_por: PC8<Output<PushPull>>,
+    por_hold_ticks: u32,
+    shutdown_timeout_ticks: u32,
     power_state: PowerState,
+    fault: Option<FaultCode>,
+    fault_latched: bool,
+    battery_low: bool,
+    supply_ok: bool,
+    os_ready: bool,
+    tick: u32,
+    power_good: [bool; RAIL_COUNT],
+    log: PowerLog,
 }
 
-struct PowerSupplies {
-    en_1v0: PC0<Output<PushPull>>,
-    pg_1v0: PC4<Input<Floating>>,
-    en_1v5: PC1<Output<PushPull>>,
-    pg_1v5: PC5<Input<Floating>>,
-    en_1v8: PC2<Output<PushPull>>,
-    pg_1v8: PC6<Input<Floating>>,
-    en_3v3: PC3<Output<PushPull>>,
-    pg_3v3: PC7<Input<Floating>>,
-    zynq_por: PC8<Output<PushPull>>,
+pub const RAIL_COUNT: usize = 4;
+
+/// One entry in the rail sequencing table.
+///
+/// Rails are listed in power-up order, and only depend on rails listed
+/// before them. Power-down walks the table in reverse, turning a rail off
+/// once nothing that depends on it is still up.
+pub struct Rail {
+    pub name: &'static str,
+    pub enable: PC<Output<PushPull>>,
+    pub power_good: PC<Input<Floating>>,
+    /// Table indices of the rails that must be good before enabling this one
+    pub depends_on: &'static [usize],
+    /// Ticks to wait after power-good before dependents may start
+    pub settle_ticks: u32,
+    /// Ticks to wait for power-good to change before faulting
+    pub timeout_ticks: u32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RailState {
+    Off,
+    Ramping { since: u32 },
+    Settling { since: u32 },
+    Good,
+    Disabling { since: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Up,
+    Down,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultReason {
+    /// Power-good didn't follow the enable within the rail's timeout
+    Timeout { direction: Direction, waited: u32 },
+    /// Power-good dropped while the Zynq was running
+    Dropout,
+}
+
+/// Records which rail failed and how
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaultCode {
+    /// Index into the rail table
+    pub rail: usize,
+    pub reason: FaultReason,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerUpError {
+    /// A rail dropped out while on, and nobody has acknowledged it yet
+    FaultLatched,
+    /// The battery is below the level it's safe to start from
+    LowBattery,
+    /// There's no battery, and USB can't supply the board on its own
+    WeakSupply,
+}
+
+/// Coarse view of the power state, for everything outside the sequencer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Off,
+    PoweringUp,
+    /// Rails up and POR released
+    Running,
+    Resetting,
+    ShuttingDown,
+    PoweringDown,
+    Fault,
+}
+
+#[derive(Clone, Copy, PartialEq)]
 enum PowerState {
-    Stage0Up,
-    Stage1Up,
-    Stage2Up,
-    Stage3Up,
-    On,
     Off,
-    Stage3Down,
-    Stage2Down,
-    Stage1Down,
-    Stage0Down,
+    SequencingUp,
+    PorHold { since: u32 },
+    On,
+    Resetting { since: u32, hold_ticks: u32 },
+    ShutdownRequested { since: u32, acknowledged: bool },
+    SequencingDown,
+    Fault,
 }
 
+/// EXTI lines of the power-good inputs, PC4 to PC7
+const POWER_GOOD_LINES: [u8; RAIL_COUNT] = [4, 5, 6, 7];
+
+/// Ticks to keep POR asserted once every rail is good
+const POR_HOLD_TICKS: u32 = 1;
+/// Ticks to pulse POR for a warm reset, unless asked for something else
+pub const RESET_HOLD_TICKS: u32 = 2;
+/// Ticks to wait for the OS to acknowledge a shutdown request
+const SHUTDOWN_ACK_TIMEOUT_TICKS: u32 = 50;
+/// Ticks to wait for the OS to halt once it acknowledged, by default
+const SHUTDOWN_TIMEOUT_TICKS: u32 = 300;
+/// Longest POR pulse the host may ask for
+const MAX_RESET_HOLD_TICKS: u32 = 50;
+/// Longest the host may let the OS take to halt
+const MAX_SHUTDOWN_TIMEOUT_TICKS: u32 = 6000;
+
 impl ZynqState {
     pub fn new(
         pc0: PC0<Output<PushPull>>,
@@ -49,158 +141,551 @@ impl ZynqState {
         pc6: PC6<Input<Floating>>,
         pc7: PC7<Input<Floating>>,
         pc8: PC8<Output<PushPull>>,
+        exti: &mut Exti,
+        syscfg: &mut SYSCFG,
     ) -> Self {
-        Self {
-            power_supplies: PowerSupplies {
-                en_1v0: pc0,
-                pg_1v0: pc4,
-                en_1v5: pc1,
-                pg_1v5: pc5,
-                en_1v8: pc2,
-                pg_1v8: pc6,
-                en_3v3: pc3,
-                pg_3v3: pc7,
-                zynq_por: pc8,
+        // power-good edges get timestamped from the EXTI interrupt
+        for &line in POWER_GOOD_LINES.iter() {
+            exti.listen_gpio(
+                syscfg,
+                Port::PC,
+                GpioLine::from_raw_line(line).unwrap(),
+                TriggerEdge::Both,
+            );
+        }
+
+        // Zynq-7000 wants VCCINT, then VCCAUX, then VCCO/DDR
+        let rails = [
+            Rail {
+                name: "1V0",
+                enable: pc0.downgrade(),
+                power_good: pc4.downgrade(),
+                depends_on: &[],
+                settle_ticks: 1,
+                timeout_ticks: 10,
             },
+            Rail {
+                name: "1V8",
+                enable: pc2.downgrade(),
+                power_good: pc6.downgrade(),
+                depends_on: &[0],
+                settle_ticks: 1,
+                timeout_ticks: 10,
+            },
+            Rail {
+                name: "1V5",
+                enable: pc1.downgrade(),
+                power_good: pc5.downgrade(),
+                depends_on: &[1],
+                settle_ticks: 1,
+                timeout_ticks: 10,
+            },
+            Rail {
+                name: "3V3",
+                enable: pc3.downgrade(),
+                power_good: pc7.downgrade(),
+                depends_on: &[1],
+                settle_ticks: 1,
+                timeout_ticks: 10,
+            },
+        ];
+        Self {
+            rails,
+            rail_states: [RailState::Off; RAIL_COUNT],
+            zynq_por: pc8,
+            por_hold_ticks: POR_HOLD_TICKS,
+            shutdown_timeout_ticks: SHUTDOWN_TIMEOUT_TICKS,
             power_state: PowerState::Off,
+            fault: None,
+            fault_latched: false,
+            battery_low: false,
+            // until something shows the board can be supplied
+            supply_ok: false,
+            os_ready: false,
+            tick: 0,
+            power_good: [false; RAIL_COUNT],
+            log: PowerLog::new(),
         }
     }
 
-    pub fn power_up(&mut self) {
+    pub fn power_up(&mut self) -> Result<(), PowerUpError> {
+        if self.fault_latched {
+            return Err(PowerUpError::FaultLatched);
+        }
+        if self.battery_low && !self.is_power_on() {
+            return Err(PowerUpError::LowBattery);
+        }
+        if !self.supply_ok && !self.is_power_on() {
+            return Err(PowerUpError::WeakSupply);
+        }
         power::set_sleep_power_state(true);
         self.power_state = match self.power_state {
             // if we're already powering on, don't do anything
             PowerState::On
-            | PowerState::Stage0Up
-            | PowerState::Stage1Up
-            | PowerState::Stage2Up
-            | PowerState::Stage3Up => self.power_state.clone(),
-            PowerState::Off
-            | PowerState::Stage3Down
-            | PowerState::Stage2Down
-            | PowerState::Stage1Down
-            | PowerState::Stage0Down => {
+            | PowerState::SequencingUp
+            | PowerState::PorHold { .. }
+            | PowerState::Resetting { .. }
+            | PowerState::ShutdownRequested { .. } => self.power_state,
+            PowerState::Off | PowerState::Fault | PowerState::SequencingDown => {
                 // if we're off, start the sequence
-                self.power_supplies.zynq_por.set_low().unwrap();
-                PowerState::Stage0Up
+                self.zynq_por.set_low().unwrap();
+                self.fault = None;
+                self.os_ready = false;
+                self.log.begin(Direction::Up, self.now_us());
+                PowerState::SequencingUp
             }
         };
+        Ok(())
     }
 
     pub fn power_down(&mut self) {
         self.power_state = match self.power_state {
-            PowerState::Off
-            | PowerState::Stage3Down
-            | PowerState::Stage2Down
-            | PowerState::Stage1Down
-            | PowerState::Stage0Down => self.power_state.clone(),
+            PowerState::Off | PowerState::Fault | PowerState::SequencingDown => self.power_state,
             PowerState::On
-            | PowerState::Stage0Up
-            | PowerState::Stage1Up
-            | PowerState::Stage2Up
-            | PowerState::Stage3Up => {
+            | PowerState::SequencingUp
+            | PowerState::PorHold { .. }
+            | PowerState::Resetting { .. }
+            | PowerState::ShutdownRequested { .. } => {
                 // if we're on, start the sequence
-                self.power_supplies.zynq_por.set_low().unwrap();
-                PowerState::Stage3Down
+                self.start_down()
             }
         }
     }
 
-    pub fn power_toggle(&mut self) {
+    pub fn is_power_on(&self) -> bool {
         match self.power_state {
-            PowerState::Stage3Down
-            | PowerState::Stage2Down
-            | PowerState::Stage1Down
-            | PowerState::Stage0Down
-            | PowerState::Off => self.power_up(),
+            PowerState::SequencingDown | PowerState::Fault | PowerState::Off => false,
             PowerState::On
-            | PowerState::Stage0Up
-            | PowerState::Stage1Up
-            | PowerState::Stage2Up
-            | PowerState::Stage3Up => self.power_down(),
+            | PowerState::SequencingUp
+            | PowerState::PorHold { .. }
+            | PowerState::Resetting { .. }
+            | PowerState::ShutdownRequested { .. } => true,
         }
     }
 
-    pub fn is_power_on(&self) -> bool {
+    pub fn status(&self) -> Status {
         match self.power_state {
-            PowerState::Stage3Down
-            | PowerState::Stage2Down
-            | PowerState::Stage1Down
-            | PowerState::Stage0Down
-            | PowerState::Off => false,
-            PowerState::On
-            | PowerState::Stage0Up
-            | PowerState::Stage1Up
-            | PowerState::Stage2Up
-            | PowerState::Stage3Up => true,
+            PowerState::Off => Status::Off,
+            PowerState::SequencingUp | PowerState::PorHold { .. } => Status::PoweringUp,
+            PowerState::On => Status::Running,
+            PowerState::Resetting { .. } => Status::Resetting,
+            PowerState::ShutdownRequested { .. } => Status::ShuttingDown,
+            PowerState::SequencingDown => Status::PoweringDown,
+            PowerState::Fault => Status::Fault,
         }
     }
 
-    pub fn tick(&mut self, _: u32) {
-        self.power_state = match self.power_state {
-            PowerState::On | PowerState::Off => self.power_state.clone(),
-            PowerState::Stage0Up => {
-                self.power_supplies.en_1v0.set_high().unwrap();
-                PowerState::Stage1Up
+    /// Starts a graceful shutdown, where the OS gets a chance to halt before
+    /// the rails go down. Returns true if the caller should send the OS the
+    /// shutdown request.
+    pub fn request_shutdown(&mut self, tick: u32) -> bool {
+        match self.power_state {
+            PowerState::On => {
+                self.power_state = PowerState::ShutdownRequested {
+                    since: tick,
+                    acknowledged: false,
+                };
+                true
+            }
+            // nothing is running yet, or it's getting reset
+            PowerState::SequencingUp
+            | PowerState::PorHold { .. }
+            | PowerState::Resetting { .. } => {
+                self.power_down();
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// The OS acknowledged the shutdown request, give it the full timeout
+    pub fn shutdown_acknowledged(&mut self, tick: u32) {
+        if let PowerState::ShutdownRequested {
+            acknowledged: false,
+            ..
+        } = self.power_state
+        {
+            self.power_state = PowerState::ShutdownRequested {
+                since: tick,
+                acknowledged: true,
+            };
+        }
+    }
+
+    /// Refuses power-ups while the battery is too low to start from
+    pub fn set_battery_low(&mut self, low: bool) {
+        self.battery_low = low;
+    }
+
+    /// Refuses power-ups while nothing can supply the board
+    pub fn set_supply_ok(&mut self, ok: bool) {
+        self.supply_ok = ok;
+    }
+
+    /// The OS spoke up over the link, so it's done booting
+    pub fn os_alive(&mut self) {
+        if self.power_state == PowerState::On {
+            self.os_ready = true;
+        }
+    }
+
+    /// True once the OS has been heard from since the last power-up or reset
+    pub fn os_ready(&self) -> bool {
+        self.os_ready
+    }
+
+    /// The OS has halted, whether we asked it to or not
+    pub fn halted(&mut self) {
+        match self.power_state {
+            PowerState::On | PowerState::ShutdownRequested { .. } => self.power_down(),
+            _ => {}
+        }
+    }
+
+    pub fn set_shutdown_timeout(&mut self, ticks: u32) {
+        self.shutdown_timeout_ticks = ticks.min(MAX_SHUTDOWN_TIMEOUT_TICKS);
+    }
+
+    /// Warm resets the Zynq by holding POR low with the rails left up.
+    ///
+    /// Only does anything once the Zynq is fully on, so a reset racing with
+    /// a power-up or power-down is dropped. Returns whether it started.
+    pub fn reset(&mut self, tick: u32, hold_ticks: u32) -> bool {
+        match self.power_state {
+            PowerState::On => {
+                self.zynq_por.set_low().unwrap();
+                self.os_ready = false;
+                self.power_state = PowerState::Resetting {
+                    since: tick,
+                    hold_ticks: hold_ticks.min(MAX_RESET_HOLD_TICKS),
+                };
+                true
             }
-            PowerState::Stage1Up => {
-                if self.power_supplies.pg_1v0.is_high().unwrap() {
-                    self.power_supplies.en_1v8.set_high().unwrap();
-                    PowerState::Stage2Up
+            _ => false,
+        }
+    }
+
+    /// The fault that stopped the last sequence, if we're sitting in Fault
+    pub fn fault(&self) -> Option<&FaultCode> {
+        self.fault.as_ref()
+    }
+
+    /// True while a rail dropout is blocking power-up
+    pub fn fault_latched(&self) -> bool {
+        self.fault_latched
+    }
+
+    pub fn acknowledge_fault(&mut self) {
+        self.fault_latched = false;
+    }
+
+    /// Timestamps power-good edges, called from the EXTI interrupt
+    pub fn handle_power_good_interrupt(&mut self, tick: u32) {
+        let mut pending = false;
+        for &line in POWER_GOOD_LINES.iter() {
+            let line = GpioLine::from_raw_line(line).unwrap();
+            if Exti::is_pending(line) {
+                Exti::unpend(line);
+                pending = true;
+            }
+        }
+        if pending {
+            self.tick = tick;
+            self.sample_power_good();
+        }
+    }
+
+    pub fn tick(&mut self, tick: u32) {
+        self.tick = tick;
+        // in case an edge got past the interrupt
+        self.sample_power_good();
+        self.power_state = match self.power_state {
+            PowerState::Off | PowerState::Fault => self.power_state,
+            PowerState::On => match self.dropped_rail() {
+                Some(rail) => self.enter_dropout(rail),
+                None => self.power_state,
+            },
+            PowerState::SequencingUp => match self.sequence_up(tick) {
+                Err(fault) => self.enter_fault(fault),
+                Ok(true) => PowerState::PorHold { since: tick },
+                Ok(false) => self.power_state,
+            },
+            PowerState::Resetting { since, hold_ticks } => {
+                if let Some(rail) = self.dropped_rail() {
+                    self.enter_dropout(rail)
+                } else if tick.wrapping_sub(since) >= hold_ticks {
+                    self.zynq_por.set_high().unwrap();
+                    PowerState::On
                 } else {
-                    self.power_state.clone()
+                    self.power_state
                 }
             }
-            PowerState::Stage2Up => {
-                if self.power_supplies.pg_1v8.is_high().unwrap() {
-                    self.power_supplies.en_1v5.set_high().unwrap();
-                    self.power_supplies.en_3v3.set_high().unwrap();
-                    PowerState::Stage3Up
+            PowerState::ShutdownRequested {
+                since,
+                acknowledged,
+            } => {
+                let timeout = if acknowledged {
+                    self.shutdown_timeout_ticks
                 } else {
-                    self.power_state.clone()
+                    SHUTDOWN_ACK_TIMEOUT_TICKS
+                };
+                if let Some(rail) = self.dropped_rail() {
+                    self.enter_dropout(rail)
+                } else if tick.wrapping_sub(since) >= timeout {
+                    // the OS is done, or isn't listening, either way cut it
+                    self.start_down()
+                } else {
+                    self.power_state
                 }
             }
-            PowerState::Stage3Up => {
-                if self.power_supplies.pg_1v5.is_high().unwrap()
-                    && self.power_supplies.pg_3v3.is_high().unwrap()
-                {
-                    self.power_supplies.zynq_por.set_high().unwrap();
+            PowerState::PorHold { since } => {
+                if let Some(rail) = self.dropped_rail() {
+                    self.enter_dropout(rail)
+                } else if tick.wrapping_sub(since) >= self.por_hold_ticks {
+                    self.zynq_por.set_high().unwrap();
+                    self.log.record(self.now_us(), EventKind::PorRelease);
+                    self.log.end();
                     PowerState::On
                 } else {
-                    self.power_state.clone()
+                    self.power_state
                 }
             }
-            PowerState::Stage3Down => {
-                self.power_supplies.en_1v5.set_low().unwrap();
-                self.power_supplies.en_3v3.set_low().unwrap();
-                PowerState::Stage2Down
-            }
-            PowerState::Stage2Down => {
-                if self.power_supplies.pg_1v5.is_low().unwrap()
-                    && self.power_supplies.pg_3v3.is_low().unwrap()
-                {
-                    self.power_supplies.en_1v8.set_low().unwrap();
-                    PowerState::Stage1Down
+            PowerState::SequencingDown => match self.sequence_down(tick) {
+                Err(fault) => self.enter_fault(fault),
+                Ok(true) => {
+                    power::set_sleep_power_state(false);
+                    self.log.end();
+                    PowerState::Off
+                }
+                Ok(false) => self.power_state,
+            },
+        }
+    }
+
+    fn now_us(&self) -> u32 {
+        time::micros(self.tick)
+    }
+
+    fn sample_power_good(&mut self) {
+        let now_us = self.now_us();
+        for i in 0..RAIL_COUNT {
+            let good = self.rails[i].power_good.is_high().unwrap();
+            if good != self.power_good[i] {
+                self.power_good[i] = good;
+                let kind = if good {
+                    EventKind::PowerGoodRise(i)
                 } else {
-                    self.power_state.clone()
+                    EventKind::PowerGoodFall(i)
+                };
+                self.log.record(now_us, kind);
+            }
+        }
+    }
+
+    fn start_down(&mut self) -> PowerState {
+        self.zynq_por.set_low().unwrap();
+        self.os_ready = false;
+        let now_us = self.now_us();
+        self.log.begin(Direction::Down, now_us);
+        self.log.record(now_us, EventKind::PorAssert);
+        PowerState::SequencingDown
+    }
+
+    /// First rail in the table whose power-good has gone low
+    fn dropped_rail(&self) -> Option<usize> {
+        self.rails
+            .iter()
+            .position(|rail| rail.power_good.is_low().unwrap())
+    }
+
+    /// Walks the table forward, returns true once every rail is good
+    fn sequence_up(&mut self, tick: u32) -> Result<bool, FaultCode> {
+        for i in 0..RAIL_COUNT {
+            let rail = &mut self.rails[i];
+            self.rail_states[i] = match self.rail_states[i] {
+                RailState::Off | RailState::Disabling { .. } => {
+                    let rail_states = &self.rail_states;
+                    if rail
+                        .depends_on
+                        .iter()
+                        .all(|&dep| rail_states[dep] == RailState::Good)
+                    {
+                        rail.enable.set_high().unwrap();
+                        self.log.record(time::micros(tick), EventKind::Enable(i));
+                        RailState::Ramping { since: tick }
+                    } else {
+                        self.rail_states[i]
+                    }
+                }
+                RailState::Ramping { since } => {
+                    let waited = tick.wrapping_sub(since);
+                    if rail.power_good.is_high().unwrap() {
+                        RailState::Settling { since: tick }
+                    } else if waited >= rail.timeout_ticks {
+                        return Err(FaultCode {
+                            rail: i,
+                            reason: FaultReason::Timeout {
+                                direction: Direction::Up,
+                                waited,
+                            },
+                        });
+                    } else {
+                        self.rail_states[i]
+                    }
+                }
+                RailState::Settling { since } => {
+                    if tick.wrapping_sub(since) >= rail.settle_ticks {
+                        RailState::Good
+                    } else {
+                        self.rail_states[i]
+                    }
+                }
+                RailState::Good => RailState::Good,
+            };
+        }
+        Ok(self.rail_states.iter().all(|&s| s == RailState::Good))
+    }
+
+    /// Walks the table backward, returns true once every rail is off
+    fn sequence_down(&mut self, tick: u32) -> Result<bool, FaultCode> {
+        for i in (0..RAIL_COUNT).rev() {
+            self.rail_states[i] = match self.rail_states[i] {
+                RailState::Off => RailState::Off,
+                RailState::Ramping { .. } | RailState::Settling { .. } | RailState::Good => {
+                    let rails = &self.rails;
+                    let rail_states = &self.rail_states;
+                    // only drop a rail once everything that depends on it is off
+                    let dependents_up = (i + 1..RAIL_COUNT).any(|j| {
+                        rails[j].depends_on.contains(&i) && rail_states[j] != RailState::Off
+                    });
+                    if dependents_up {
+                        self.rail_states[i]
+                    } else {
+                        self.rails[i].enable.set_low().unwrap();
+                        self.log.record(time::micros(tick), EventKind::Disable(i));
+                        RailState::Disabling { since: tick }
+                    }
+                }
+                RailState::Disabling { since } => {
+                    let rail = &self.rails[i];
+                    let waited = tick.wrapping_sub(since);
+                    if rail.power_good.is_low().unwrap() {
+                        RailState::Off
+                    } else if waited >= rail.timeout_ticks {
+                        return Err(FaultCode {
+                            rail: i,
+                            reason: FaultReason::Timeout {
+                                direction: Direction::Down,
+                                waited,
+                            },
+                        });
+                    } else {
+                        self.rail_states[i]
+                    }
+                }
+            };
+        }
+        Ok(self.rail_states.iter().all(|&s| s == RailState::Off))
+    }
+
+    fn enter_dropout(&mut self, rail: usize) -> PowerState {
+        // a rail browned out or tripped, get everything off and stay off
+        // until someone acknowledges it
+        self.fault_latched = true;
+        self.enter_fault(FaultCode {
+            rail,
+            reason: FaultReason::Dropout,
+        })
+    }
+
+    fn enter_fault(&mut self, fault: FaultCode) -> PowerState {
+        // hold the Zynq in reset and unwind the rails in reverse order,
+        // without waiting on power-good since we can't trust it anymore
+        self.zynq_por.set_low().unwrap();
+        for i in (0..RAIL_COUNT).rev() {
+            if self.rail_states[i] != RailState::Off {
+                self.rails[i].enable.set_low().unwrap();
+                self.rail_states[i] = RailState::Off;
+            }
+        }
+        power::set_sleep_power_state(false);
+        self.log.record(self.now_us(), EventKind::Fault);
+        self.log.end();
+        self.fault = Some(fault);
+        PowerState::Fault
+    }
+
+    /// Dumps the kept sequencing traces, oldest first, and the fault that
+    /// stopped the last one if we're sitting in Fault
+    pub fn write_traces<W: Write>(&self, w: &mut W) -> fmt::Result {
+        for trace in self.log.traces() {
+            let direction = match trace.direction {
+                Direction::Up => "up",
+                Direction::Down => "down",
+            };
+            writeln!(w, "@smc trace {} at {}us", direction, trace.start_us)?;
+            for event in trace.events() {
+                let offset = event.at_us.wrapping_sub(trace.start_us);
+                write!(w, "@smc   +{}us ", offset)?;
+                match event.kind {
+                    EventKind::Enable(rail) => write!(w, "{} enable", self.rails[rail].name)?,
+                    EventKind::Disable(rail) => write!(w, "{} disable", self.rails[rail].name)?,
+                    EventKind::PowerGoodRise(rail) => {
+                        write!(w, "{} pg-rise", self.rails[rail].name)?
+                    }
+                    EventKind::PowerGoodFall(rail) => {
+                        write!(w, "{} pg-fall", self.rails[rail].name)?
+                    }
+                    EventKind::PorRelease => w.write_str("por-release")?,
+                    EventKind::PorAssert => w.write_str("por-assert")?,
+                    EventKind::Fault => w.write_str("fault")?,
                 }
+                writeln!(w)?;
             }
-            PowerState::Stage1Down => {
-                if self.power_supplies.pg_1v8.is_low().unwrap() {
-                    self.power_supplies.en_1v0.set_low().unwrap();
-                    PowerState::Stage0Down
-                } else {
-                    self.power_state.clone()
+        }
+        if let Some(fault) = self.fault {
+            write!(w, "@smc fault {} ", self.rails[fault.rail].name)?;
+            match fault.reason {
+                FaultReason::Timeout { direction, waited } => {
+                    let direction = match direction {
+                        Direction::Up => "up",
+                        Direction::Down => "down",
+                    };
+                    write!(
+                        w,
+                        "timeout {} waited={}ms",
+                        direction,
+                        waited * (time::TICK_US / 1000)
+                    )?;
                 }
+                FaultReason::Dropout => w.write_str("dropout")?,
             }
-            PowerState::Stage0Down => {
-                if self.power_supplies.pg_1v0.is_low().unwrap() {
-                    power::set_sleep_power_state(false);
-                    PowerState::Off
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    /// Dumps ramp time statistics for every rail
+    pub fn write_stats<W: Write>(&self, w: &mut W) -> fmt::Result {
+        for (i, rail) in self.rails.iter().enumerate() {
+            for &(edge, stats) in [("rise", &self.log.rise[i]), ("fall", &self.log.fall[i])].iter()
+            {
+                if stats.count == 0 {
+                    writeln!(w, "@smc stats {} {} n=0", rail.name, edge)?;
                 } else {
-                    self.power_state.clone()
+                    writeln!(
+                        w,
+                        "@smc stats {} {} n={} min={}us avg={}us max={}us",
+                        rail.name,
+                        edge,
+                        stats.count,
+                        stats.min_us,
+                        stats.avg_us(),
+                        stats.max_us
+                    )?;
                 }
             }
         }
+        Ok(())
     }
 }